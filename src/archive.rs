@@ -0,0 +1,637 @@
+//! Checking and renaming the images inside ZIP and TAR archives.
+//!
+//! Archives are rewritten rather than journaled, so `imgfix undo` can't restore them.
+
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use tar::{EntryType, Header, PaxExtensions};
+use uncased::UncasedStr;
+use zip::ZipArchive;
+
+use crate::{
+    detect,
+    rename::{self, Renamed},
+    Checker, Error, Preserve, Result, Verdict,
+};
+
+/// The archive formats whose entries imgfix can check and rename.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveKind {
+    /// `.zip` or `.cbz`
+    Zip,
+    /// `.tar`
+    Tar,
+    /// `.tar.gz` or `.tgz`
+    TarGz,
+}
+
+impl ArchiveKind {
+    /// Recognizes an archive by its name.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = UncasedStr::new(path.extension()?.to_str()?);
+        if extension == "zip" || extension == "cbz" {
+            Some(ArchiveKind::Zip)
+        } else if extension == "tar" {
+            Some(ArchiveKind::Tar)
+        } else if extension == "tgz" {
+            Some(ArchiveKind::TarGz)
+        } else if extension == "gz" {
+            ArchiveKind::from_path(Path::new(path.file_stem()?))
+                .filter(|kind| *kind == ArchiveKind::Tar)
+                .map(|_| ArchiveKind::TarGz)
+        } else {
+            None
+        }
+    }
+}
+
+/// A file inside an archive, and what its contents say about its name.
+#[derive(Debug)]
+pub struct Entry {
+    /// The entry's name within the archive, with `/` separators.
+    pub name: String,
+    /// What checking its contents found.
+    pub verdict: Verdict,
+}
+
+/// How an entry is shown to users: `photos.zip!2023/IMG_1.png`.
+pub fn entry_path(archive: &Path, name: &str) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push("!");
+    path.push(name);
+    path.into()
+}
+
+/// `name` with its extension replaced by (or given) `extension`.
+pub fn renamed(name: &str, extension: &str) -> String {
+    let base = match detect::read_extension(Path::new(name)) {
+        Some(current) => &name[..name.len() - current.len() - 1],
+        None => name,
+    };
+    format!("{base}.{extension}")
+}
+
+/// The renames that fix an archive's mismatched entries, made together in one rewrite.
+#[derive(Debug)]
+pub struct Plan {
+    /// What becomes of each entry, in the order they were checked; `None` for those that
+    /// aren't mismatched. Paths are shown as by [`entry_path`].
+    pub outcomes: Vec<Option<Renamed>>,
+    renames: HashMap<String, String>,
+}
+
+impl Plan {
+    /// Gives each mismatched entry the extension its contents call for, skipping any whose new
+    /// name another entry already has.
+    pub fn new(archive: &Path, entries: &[Entry]) -> Self {
+        let mut names: HashSet<String> = entries.iter().map(|entry| entry.name.clone()).collect();
+        let mut renames = HashMap::new();
+        let outcomes = entries
+            .iter()
+            .map(|entry| {
+                let Verdict::Mismatch { proposed, .. } = entry.verdict else {
+                    return None;
+                };
+                let new_name = renamed(&entry.name, proposed);
+                let to = entry_path(archive, &new_name);
+                if !names.insert(new_name.clone()) {
+                    return Some(Renamed::Skipped { taken: to });
+                }
+                renames.insert(entry.name.clone(), new_name);
+                Some(Renamed::To(to))
+            })
+            .collect();
+
+        Plan { outcomes, renames }
+    }
+
+    /// Whether no entry is to be renamed, so the archive can be left alone.
+    pub fn is_empty(&self) -> bool {
+        self.renames.is_empty()
+    }
+
+    /// Rewrites the archive with its entries' new names.
+    pub fn apply(&self, archive: &Path, kind: ArchiveKind, preserve: Preserve) -> Result<()> {
+        rename_entries(archive, kind, &self.renames, preserve)
+    }
+}
+
+/// Checks every file in the archive, streaming each one rather than extracting it.
+pub fn check(checker: &Checker, path: &Path, kind: ArchiveKind) -> Result<Vec<Entry>> {
+    let file = BufReader::new(File::open(path).map_err(|e| Error::from(e).with_path(path))?);
+    match kind {
+        ArchiveKind::Zip => check_zip(checker, path, file),
+        ArchiveKind::Tar => check_tar(checker, path, file),
+        ArchiveKind::TarGz => check_tar(checker, path, GzDecoder::new(file)),
+    }
+    .map_err(|e| e.with_path(path))
+}
+
+fn check_zip(checker: &Checker, path: &Path, reader: impl Read + Seek) -> Result<Vec<Entry>> {
+    let zip_error = |e| Error::archive(path, e);
+    let mut archive = ZipArchive::new(reader).map_err(zip_error)?;
+    let mut entries = Vec::new();
+    for idx in 0..archive.len() {
+        let file = archive.by_index(idx).map_err(zip_error)?;
+        if file.is_dir() {
+            continue;
+        }
+
+        let name = file.name().to_owned();
+        let extension = detect::read_extension(Path::new(&name));
+        let verdict = checker.check_reader(&entry_path(path, &name), extension, file);
+        entries.push(Entry { name, verdict });
+    }
+    Ok(entries)
+}
+
+fn check_tar(checker: &Checker, path: &Path, reader: impl Read) -> Result<Vec<Entry>> {
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        let extension = detect::read_extension(Path::new(&name));
+        let verdict = checker.check_reader(&entry_path(path, &name), extension, entry);
+        entries.push(Entry { name, verdict });
+    }
+    Ok(entries)
+}
+
+/// Renames entries in place, from each key of `renames` to its value.
+///
+/// Every other entry is copied as is, without being decompressed; a gzipped TAR is
+/// recompressed, but the TAR inside keeps its other entries byte for byte.
+pub fn rename_entries(
+    path: &Path,
+    kind: ArchiveKind,
+    renames: &HashMap<String, String>,
+    preserve: Preserve,
+) -> Result<()> {
+    rename::replace_file(path, preserve, |out| {
+        let file = BufReader::new(File::open(path)?);
+        match kind {
+            ArchiveKind::Zip => rename_zip(path, file, out, renames),
+            ArchiveKind::Tar => rename_tar(file, out, renames),
+            ArchiveKind::TarGz => {
+                let mut out = GzEncoder::new(out, Compression::default());
+                rename_tar(GzDecoder::new(file), &mut out, renames)?;
+                out.try_finish()?;
+                Ok(())
+            }
+        }
+    })
+    .map_err(|e| e.with_path(path))
+}
+
+/// Copies a ZIP record by record, patching only the names of renamed entries and the offsets
+/// that move with them, so that every other byte (extra fields, attributes, comments) stays as
+/// it was.
+fn rename_zip(
+    path: &Path,
+    mut reader: impl Read + Seek,
+    mut out: impl Write,
+    renames: &HashMap<String, String>,
+) -> Result<()> {
+    let zip_error = |e| Error::archive(path, e);
+    let bad = |message: &str| Error::Archive {
+        path: path.into(),
+        message: message.into(),
+    };
+
+    // (name, local header, central header), each position from the start of the file
+    let mut entries = Vec::new();
+    {
+        let mut archive = ZipArchive::new(&mut reader).map_err(zip_error)?;
+        for idx in 0..archive.len() {
+            let file = archive.by_index_raw(idx).map_err(zip_error)?;
+            let name = file.name().to_owned();
+            entries.push((name, file.header_start(), file.central_header_start()));
+        }
+    }
+
+    let end = find_end_of_central_directory(&mut reader)
+        .map_err(Error::from)?
+        .ok_or_else(|| bad("no end of central directory"))?;
+    if end >= 20 && read_at(&mut reader, end - 20, 4)? == ZIP64_END_LOCATOR {
+        return Err(bad("can't rename entries in a ZIP64 archive"));
+    }
+    let central_start = entries
+        .iter()
+        .map(|(_, _, central)| *central)
+        .min()
+        .unwrap_or(end);
+
+    // Anything before the first entry, such as a self-extractor, is copied too.
+    entries.sort_by_key(|(_, local, _)| *local);
+    let first = entries
+        .first()
+        .map_or(central_start, |(_, local, _)| *local);
+    copy_range(&mut reader, &mut out, 0, first)?;
+
+    // How far each entry's local header moves as names before it change length.
+    let mut shifts = HashMap::new();
+    let mut shift = 0i64;
+    for (idx, (name, local, _)) in entries.iter().enumerate() {
+        let next = entries
+            .get(idx + 1)
+            .map_or(central_start, |(_, next, _)| *next);
+        shifts.insert(*local, shift);
+        let Some(new_name) = renames.get(name) else {
+            copy_range(&mut reader, &mut out, *local, next)?;
+            continue;
+        };
+
+        let mut header = read_at(&mut reader, *local, 30)?;
+        if header[..4] != LOCAL_HEADER {
+            return Err(bad("bad local header"));
+        }
+        let name_len = u16_at(&header, 26) as u64;
+        let extra_len = u16_at(&header, 28) as u64;
+        let extra = read_at(&mut reader, *local + 30 + name_len, extra_len)?;
+        let extra = without_unicode_path(&extra);
+        set_utf8(&mut header, 6, new_name);
+        set_u16(&mut header, 26, new_name.len())?;
+        set_u16(&mut header, 28, extra.len())?;
+        out.write_all(&header)?;
+        out.write_all(new_name.as_bytes())?;
+        out.write_all(&extra)?;
+
+        let rest = *local + 30 + name_len + extra_len;
+        copy_range(&mut reader, &mut out, rest, next)?;
+        shift += (new_name.len() + extra.len()) as i64 - (name_len + extra_len) as i64;
+    }
+
+    entries.sort_by_key(|(_, _, central)| *central);
+    let mut central_len = 0u64;
+    let mut position = central_start;
+    for (name, local, central) in &entries {
+        let mut header = read_at(&mut reader, *central, 46)?;
+        if header[..4] != CENTRAL_HEADER {
+            return Err(bad("bad central directory header"));
+        }
+        let name_len = u16_at(&header, 28) as u64;
+        let extra_len = u16_at(&header, 30) as u64;
+        let comment_len = u16_at(&header, 32) as u64;
+        let mut rest = read_at(
+            &mut reader,
+            *central + 46,
+            name_len + extra_len + comment_len,
+        )?;
+
+        let offset = u32_at(&header, 42) as i64 + shifts[local];
+        let offset = u32::try_from(offset).map_err(|_| bad("entry offset out of range"))?;
+        header[42..46].copy_from_slice(&offset.to_le_bytes());
+        if let Some(new_name) = renames.get(name) {
+            let (extra, comment) = rest[name_len as usize..].split_at(extra_len as usize);
+            let extra = without_unicode_path(extra);
+            set_utf8(&mut header, 8, new_name);
+            set_u16(&mut header, 28, new_name.len())?;
+            set_u16(&mut header, 30, extra.len())?;
+            rest = [new_name.as_bytes(), &extra, comment].concat();
+        }
+
+        out.write_all(&header)?;
+        out.write_all(&rest)?;
+        central_len += 46 + rest.len() as u64;
+        position = *central + 46 + name_len + extra_len + comment_len;
+    }
+    copy_range(&mut reader, &mut out, position, end)?;
+    central_len += end - position;
+
+    let mut end_record = read_at(&mut reader, end, 22)?;
+    let central_offset = u32_at(&end_record, 16) as i64 + shift;
+    let central_offset =
+        u32::try_from(central_offset).map_err(|_| bad("central directory out of range"))?;
+    let central_len = u32::try_from(central_len).map_err(|_| bad("central directory too big"))?;
+    end_record[12..16].copy_from_slice(&central_len.to_le_bytes());
+    end_record[16..20].copy_from_slice(&central_offset.to_le_bytes());
+    out.write_all(&end_record)?;
+    let archive_len = reader.seek(SeekFrom::End(0))?;
+    copy_range(&mut reader, &mut out, end + 22, archive_len)?;
+    Ok(())
+}
+
+const LOCAL_HEADER: [u8; 4] = *b"PK\x03\x04";
+const CENTRAL_HEADER: [u8; 4] = *b"PK\x01\x02";
+const END_OF_CENTRAL_DIRECTORY: [u8; 4] = *b"PK\x05\x06";
+const ZIP64_END_LOCATOR: [u8; 4] = *b"PK\x06\x07";
+
+/// The position of the end of central directory record, which may be followed by a comment.
+fn find_end_of_central_directory(reader: &mut (impl Read + Seek)) -> io::Result<Option<u64>> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    let tail_len = len.min(22 + u16::MAX as u64);
+    let tail = read_at(reader, len - tail_len, tail_len)?;
+    let found = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&idx| tail[idx..idx + 4] == END_OF_CENTRAL_DIRECTORY);
+    Ok(found.map(|idx| len - tail_len + idx as u64))
+}
+
+fn read_at(reader: &mut (impl Read + Seek), position: u64, len: u64) -> io::Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(position))?;
+    let mut bytes = vec![0; len as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn copy_range(
+    reader: &mut (impl Read + Seek),
+    out: &mut impl Write,
+    start: u64,
+    end: u64,
+) -> io::Result<()> {
+    reader.seek(SeekFrom::Start(start))?;
+    let copied = io::copy(&mut reader.take(end.saturating_sub(start)), out)?;
+    match copied == end.saturating_sub(start) {
+        true => Ok(()),
+        false => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+fn u16_at(bytes: &[u8], idx: usize) -> u16 {
+    u16::from_le_bytes([bytes[idx], bytes[idx + 1]])
+}
+
+fn u32_at(bytes: &[u8], idx: usize) -> u32 {
+    u32::from_le_bytes(bytes[idx..idx + 4].try_into().unwrap())
+}
+
+fn set_u16(bytes: &mut [u8], idx: usize, value: usize) -> io::Result<()> {
+    let value = u16::try_from(value).map_err(|_| io::Error::other("ZIP field too long"))?;
+    bytes[idx..idx + 2].copy_from_slice(&value.to_le_bytes());
+    Ok(())
+}
+
+/// Marks a header's name as UTF-8 if it needs to be; `flags` is where its flags are.
+fn set_utf8(header: &mut [u8], flags: usize, name: &str) {
+    if !name.is_ascii() {
+        header[flags + 1] |= 0x08;
+    }
+}
+
+/// Extra fields without Info-ZIP's Unicode path, which would otherwise override a new name.
+fn without_unicode_path(extra: &[u8]) -> Vec<u8> {
+    let mut kept = Vec::with_capacity(extra.len());
+    let mut rest = extra;
+    while rest.len() >= 4 {
+        let len = 4 + u16_at(rest, 2) as usize;
+        let (field, next) = rest.split_at(len.min(rest.len()));
+        if u16_at(field, 0) != 0x7075 {
+            kept.extend_from_slice(field);
+        }
+        rest = next;
+    }
+    kept.extend_from_slice(rest);
+    kept
+}
+
+/// Copies a TAR entry by entry without interpreting it, so that nothing but the renamed names
+/// changes.
+fn rename_tar(reader: impl Read, out: impl Write, renames: &HashMap<String, String>) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    let mut builder = tar::Builder::new(out);
+
+    // GNU long names and PAX headers precede the entry they describe.
+    let mut extensions: Vec<(Header, Vec<u8>)> = Vec::new();
+    for entry in archive.entries()?.raw(true) {
+        let mut entry = entry?;
+        let mut header = entry.header().clone();
+        let kind = header.entry_type();
+        if matches!(
+            kind,
+            EntryType::GNULongName | EntryType::GNULongLink | EntryType::XHeader
+        ) {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            extensions.push((header, data));
+            continue;
+        }
+
+        let name = full_name(&header, &extensions);
+        let Some(new_name) = renames.get(&*name) else {
+            for (header, data) in extensions.drain(..) {
+                builder.append(&header, &*data)?;
+            }
+            builder.append(&header, entry)?;
+            continue;
+        };
+
+        // The old name may live in a long name or PAX header; drop it there and let the
+        // builder store the new one however it needs to.
+        for (header, data) in extensions.drain(..) {
+            match header.entry_type() {
+                EntryType::GNULongName => {}
+                EntryType::XHeader => {
+                    let records: Vec<_> = PaxExtensions::new(&data)
+                        .filter_map(|record| record.ok())
+                        .filter(|record| record.key_bytes() != b"path")
+                        .filter_map(|record| Some((record.key().ok()?, record.value_bytes())))
+                        .collect();
+                    builder.append_pax_extensions(records)?;
+                }
+                _ => builder.append(&header, &*data)?,
+            }
+        }
+        builder.append_data(&mut header, new_name, entry)?;
+    }
+
+    builder.into_inner()?;
+    Ok(())
+}
+
+/// The name of a TAR entry, taking any long name or PAX header before it into account.
+fn full_name<'a>(header: &'a Header, extensions: &'a [(Header, Vec<u8>)]) -> Cow<'a, str> {
+    for (extension, data) in extensions.iter().rev() {
+        match extension.entry_type() {
+            EntryType::GNULongName => {
+                let name = data.strip_suffix(b"\0").unwrap_or(data);
+                return String::from_utf8_lossy(name);
+            }
+            EntryType::XHeader => {
+                let path = PaxExtensions::new(data)
+                    .filter_map(|record| record.ok())
+                    .find(|record| record.key_bytes() == b"path");
+                if let Some(path) = path {
+                    return String::from_utf8_lossy(path.value_bytes());
+                }
+            }
+            _ => {}
+        }
+    }
+    match header.path_bytes() {
+        Cow::Borrowed(name) => String::from_utf8_lossy(name),
+        Cow::Owned(name) => Cow::Owned(String::from_utf8_lossy(&name).into_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{Cursor, Read, Write},
+        path::Path,
+    };
+
+    use tar::{Builder, Header};
+    use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+    use image::ImageFormat;
+
+    use super::{entry_path, rename_tar, rename_zip, renamed, Entry, Plan};
+    use crate::{rename::Renamed, Format, Verdict};
+
+    #[test]
+    fn entries_keep_their_directory() {
+        assert_eq!(renamed("2023/IMG_1.png", "jpg"), "2023/IMG_1.jpg");
+        assert_eq!(renamed("v1.2/scan", "png"), "v1.2/scan.png");
+    }
+
+    #[test]
+    fn planned_names_never_collide() {
+        let jpeg = Format::Image(ImageFormat::Jpeg);
+        let mismatch = || Verdict::Mismatch {
+            detected: jpeg,
+            proposed: "jpg",
+        };
+        let entries = [
+            ("a.jpg", Verdict::Ok { format: jpeg }),
+            ("a.png", mismatch()),
+            ("b.png", mismatch()),
+            ("b.gif", mismatch()),
+            ("notes.txt", Verdict::Unknown),
+        ]
+        .map(|(name, verdict)| Entry {
+            name: name.into(),
+            verdict,
+        });
+
+        let archive = Path::new("photos.zip");
+        let plan = Plan::new(archive, &entries);
+        let shown = |name| entry_path(archive, name);
+        assert!(matches!(
+            &plan.outcomes[..],
+            [
+                None,
+                Some(Renamed::Skipped { taken: a }),
+                Some(Renamed::To(b)),
+                Some(Renamed::Skipped { taken: b_again }),
+                None,
+            ] if *a == shown("a.jpg") && *b == shown("b.jpg") && b_again == b
+        ));
+        assert_eq!(
+            plan.renames,
+            HashMap::from([("b.png".into(), "b.jpg".into())])
+        );
+    }
+
+    #[test]
+    fn tar_entries_are_renamed_in_place() {
+        let long = format!("{}/page.png", "d".repeat(120));
+        let mut builder = Builder::new(Vec::new());
+        for (name, data) in [
+            ("a.png", &b"jpeg"[..]),
+            (&long, b"also"),
+            ("b.txt", b"text"),
+        ] {
+            let mut header = Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o640);
+            header.set_mtime(1_000_000);
+            builder.append_data(&mut header, name, data).unwrap();
+        }
+        let original = builder.into_inner().unwrap();
+
+        let renames = HashMap::from([
+            ("a.png".to_owned(), "a.jpg".to_owned()),
+            (long.clone(), long.replace(".png", ".jpg")),
+        ]);
+        let mut out = Vec::new();
+        rename_tar(Cursor::new(&original), &mut out, &renames).unwrap();
+
+        let mut archive = tar::Archive::new(Cursor::new(out));
+        let entries: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut data = String::new();
+                entry.read_to_string(&mut data).unwrap();
+                let header = entry.header();
+                let name = entry.path().unwrap().to_string_lossy().into_owned();
+                (name, data, header.mode().unwrap(), header.mtime().unwrap())
+            })
+            .collect();
+
+        assert_eq!(
+            entries[0],
+            ("a.jpg".into(), "jpeg".into(), 0o640, 1_000_000)
+        );
+        assert_eq!(entries[1].0, long.replace(".png", ".jpg"));
+        assert_eq!(
+            entries[2],
+            ("b.txt".into(), "text".into(), 0o640, 1_000_000)
+        );
+    }
+
+    #[test]
+    fn zip_entries_keep_their_bytes() {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for name in ["a.png", "b.png", "notes.txt"] {
+            let options = FileOptions::default()
+                .compression_method(CompressionMethod::Stored)
+                .unix_permissions(0o640);
+            writer.start_file_with_extra_data(name, options).unwrap();
+            // A private extra field, which a rebuilt header would lose.
+            writer.write_all(b"\xfe\xca\x04\x00kept").unwrap();
+            writer.end_extra_data().unwrap();
+            writer.write_all(name.as_bytes()).unwrap();
+        }
+        writer.set_comment("kept");
+        let original = writer.finish().unwrap().into_inner();
+
+        let renames = HashMap::from([("a.png".to_owned(), "a-renamed.jpg".to_owned())]);
+        let mut out = Vec::new();
+        rename_zip(
+            Path::new("t.zip"),
+            Cursor::new(&original),
+            &mut out,
+            &renames,
+        )
+        .unwrap();
+
+        // The untouched entries' local records are copied byte for byte.
+        let mut before = ZipArchive::new(Cursor::new(&original)).unwrap();
+        let start = before.by_index(1).unwrap().header_start() as usize;
+        let end = before.by_index(2).unwrap().header_start() as usize;
+        let untouched = &original[start..end];
+        assert!(out
+            .windows(untouched.len())
+            .any(|window| window == untouched));
+
+        let mut after = ZipArchive::new(Cursor::new(&out)).unwrap();
+        assert_eq!(after.comment(), b"kept");
+        for (idx, name) in ["a-renamed.jpg", "b.png", "notes.txt"].iter().enumerate() {
+            let original = before.by_index(idx).unwrap();
+            let (extra, mode) = (original.extra_data().to_vec(), original.unix_mode());
+            let mut entry = after.by_index(idx).unwrap();
+            assert_eq!(entry.name(), *name);
+            assert_eq!(entry.extra_data(), extra);
+            assert_eq!(entry.unix_mode(), mode);
+            let mut data = String::new();
+            entry.read_to_string(&mut data).unwrap();
+            assert_eq!(data, original.name());
+        }
+    }
+}
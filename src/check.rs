@@ -0,0 +1,345 @@
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+use image::{
+    error::{ImageFormatHint, UnsupportedErrorKind},
+    ImageError, ImageResult,
+};
+
+use crate::{config::Signature, detect, Error, Format, Preferences};
+
+/// The result of checking one file's extension against its contents.
+#[derive(Debug)]
+pub enum Verdict {
+    /// The extension suits the detected format.
+    Ok {
+        /// The format the contents were detected as.
+        format: Format,
+    },
+    /// The extension is wrong or missing; `proposed` is the one it should have.
+    Mismatch {
+        /// The format the contents were detected as.
+        detected: Format,
+        /// The extension the file should have, without a dot.
+        proposed: &'static str,
+    },
+    /// The contents don't match any known format.
+    Unknown,
+    /// The file couldn't be checked at all.
+    Error(Error),
+}
+
+/// Checks files against the formats their contents actually have.
+#[derive(Clone, Debug, Default)]
+pub struct Checker {
+    /// Propose an extension for files that have none, rather than treating them as errors.
+    pub allow_missing: bool,
+    /// User-defined signatures, consulted before the built-in ones.
+    pub signatures: &'static [Signature],
+    /// Overrides for the extension each format is given.
+    pub preferences: Preferences,
+    /// Flag allowed extensions that aren't the preferred one, in lowercase.
+    pub canonical: bool,
+    /// Fully decode every image `image` supports, reporting any that fail.
+    pub verify: bool,
+}
+
+impl Checker {
+    /// A checker with every option off and no custom signatures.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks the file at `path`, reading only its header unless verifying.
+    pub fn check_path(&self, path: &Path) -> Verdict {
+        let extension = detect::read_extension(path);
+        if extension.is_none() && !self.allow_missing {
+            return Verdict::Error(Error::bad_extension(path));
+        }
+
+        let header = match File::open(path).and_then(detect::read_header) {
+            Ok(header) => header,
+            Err(e) => return Verdict::Error(Error::from(e).with_path(path)),
+        };
+
+        let format = match self.detect(&header) {
+            Ok(format) => format,
+            Err(_) => return Verdict::Unknown,
+        };
+
+        if let Some(image_format) = format.image_format().filter(|_| self.verify) {
+            let decoded = File::open(path)
+                .map_err(|e| Error::from(e).with_path(path))
+                .and_then(
+                    |file| match image::load(BufReader::new(file), image_format) {
+                        Err(e) if !is_unverifiable(&e) => {
+                            Err(Error::damaged_image(path, format, e))
+                        }
+                        _ => Ok(()),
+                    },
+                );
+            if let Err(e) = decoded {
+                return damaged(e, self.verdict(extension, format));
+            }
+        }
+
+        self.verdict(extension, format)
+    }
+
+    /// Checks data from `reader` as though it were a file with the given extension; `name` is
+    /// what any error calls it.
+    ///
+    /// As with [`check_path`](Self::check_path), an image without an extension is an error
+    /// unless missing extensions are allowed.
+    pub fn check_reader(
+        &self,
+        name: &Path,
+        extension: Option<&OsStr>,
+        mut reader: impl Read,
+    ) -> Verdict {
+        let mut data = Vec::new();
+        let read = if self.verify {
+            reader.read_to_end(&mut data).map(|_| ())
+        } else {
+            detect::read_header(reader).map(|header| data = header)
+        };
+        if let Err(e) = read {
+            return Verdict::Error(Error::from(e).with_path(name));
+        }
+
+        let format = match self.detect(&data[..data.len().min(detect::HEADER_LEN)]) {
+            Ok(format) => format,
+            Err(_) => return Verdict::Unknown,
+        };
+        // Only once the data turns out to be an image, so that the other files an archive holds
+        // are still passed over as unknown.
+        if extension.is_none() && !self.allow_missing {
+            return Verdict::Error(Error::bad_extension(name));
+        }
+
+        if let Some(image_format) = format.image_format().filter(|_| self.verify) {
+            let decoded = image::load_from_memory_with_format(&data, image_format);
+            if let Some(e) = decoded.err().filter(|e| !is_unverifiable(e)) {
+                let e = Error::damaged_image(name, format, e);
+                return damaged(e, self.verdict(extension, format));
+            }
+        }
+
+        self.verdict(extension, format)
+    }
+
+    /// Guesses a format from a file header.
+    pub fn detect(&self, header: &[u8]) -> ImageResult<Format> {
+        match self.signatures.iter().find(|sig| sig.matches(header)) {
+            Some(signature) => Ok(Format::Custom(signature)),
+            None => detect::detect_format(header),
+        }
+    }
+
+    /// Judges an extension against an already detected format.
+    pub fn verdict(&self, extension: Option<&OsStr>, format: Format) -> Verdict {
+        match extension {
+            Some(extension) if self.is_acceptable(extension, format) => Verdict::Ok { format },
+            _ => Verdict::Mismatch {
+                detected: format,
+                proposed: self.preferences.preferred_extension(format),
+            },
+        }
+    }
+
+    fn is_acceptable(&self, extension: &OsStr, format: Format) -> bool {
+        if self.canonical {
+            extension == self.preferences.preferred_extension(format)
+        } else {
+            detect::is_allowed_extension(extension, format)
+        }
+    }
+}
+
+/// A failed decode, noting the extension the file should have when `verdict` finds it misnamed
+/// as well.
+fn damaged(error: Error, verdict: Verdict) -> Verdict {
+    match (error, verdict) {
+        (Error::Image(mut bad), Verdict::Mismatch { proposed, .. }) => {
+            bad.proposed = Some(proposed);
+            Verdict::Error(Error::Image(bad))
+        }
+        (e, _) => Verdict::Error(e),
+    }
+}
+
+/// Whether a decode failed only because `image` was built without a decoder for the format, so
+/// the file can't be verified either way.
+fn is_unverifiable(error: &ImageError) -> bool {
+    matches!(error, ImageError::Unsupported(e) if matches!(e.kind(), UnsupportedErrorKind::Format(_)))
+}
+
+impl Verdict {
+    /// Turns anything other than a definite answer into an error for `path`.
+    pub fn into_result(self, path: &Path) -> Result<Verdict, Error> {
+        match self {
+            Verdict::Unknown => Err(Error::bad_image(
+                path,
+                ImageError::Unsupported(ImageFormatHint::Unknown.into()),
+            )),
+            Verdict::Error(e) => Err(e),
+            verdict => Ok(verdict),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Cursor, path::Path};
+
+    use image::{ImageFormat, ImageOutputFormat, RgbImage};
+
+    use super::{Checker, Verdict};
+    use crate::{BadImageKind, Error, Format};
+
+    fn verifier() -> Checker {
+        Checker {
+            verify: true,
+            ..Checker::new()
+        }
+    }
+
+    fn png() -> Vec<u8> {
+        let mut png = Cursor::new(Vec::new());
+        let image = RgbImage::from_fn(16, 16, |x, y| [x as u8 * 16, y as u8 * 16, 0].into());
+        image.write_to(&mut png, ImageOutputFormat::Png).unwrap();
+        png.into_inner()
+    }
+
+    #[test]
+    fn damaged_images_are_still_checked_against_their_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trunc.jpg");
+        let png = png();
+        fs::write(&path, &png[..png.len() - 20]).unwrap();
+
+        let Verdict::Error(Error::Image(bad)) = verifier().check_path(&path) else {
+            panic!("truncated image passed verification");
+        };
+        assert_eq!(bad.kind, BadImageKind::Truncated);
+        assert_eq!(bad.format, Some(Format::Image(ImageFormat::Png)));
+        assert_eq!(bad.proposed, Some("png"));
+
+        fs::rename(&path, dir.path().join("trunc.png")).unwrap();
+        let verdict = verifier().check_path(&dir.path().join("trunc.png"));
+        assert!(matches!(verdict, Verdict::Error(Error::Image(bad)) if bad.proposed.is_none()));
+    }
+
+    fn jpeg() -> Vec<u8> {
+        let mut jpeg = Cursor::new(Vec::new());
+        let image = RgbImage::from_fn(64, 64, |x, y| [x as u8 * 4, y as u8 * 4, 0].into());
+        image
+            .write_to(&mut jpeg, ImageOutputFormat::Jpeg(90))
+            .unwrap();
+        jpeg.into_inner()
+    }
+
+    fn damage(name: &str, data: &[u8]) -> BadImageKind {
+        let extension = Path::new(name).extension();
+        match verifier().check_reader(Path::new(name), extension, data) {
+            Verdict::Error(Error::Image(bad)) => bad.kind,
+            verdict => panic!("{name} passed verification: {verdict:?}"),
+        }
+    }
+
+    #[test]
+    fn decode_failures_are_classified() {
+        let (png, jpeg) = (png(), jpeg());
+        assert!(matches!(
+            verifier().check_reader(Path::new("a.png"), Some("png".as_ref()), &png[..]),
+            Verdict::Ok { .. }
+        ));
+        assert!(matches!(
+            verifier().check_reader(Path::new("a.jpg"), Some("jpg".as_ref()), &jpeg[..]),
+            Verdict::Ok { .. }
+        ));
+
+        assert_eq!(
+            damage("a.png", &png[..png.len() - 20]),
+            BadImageKind::Truncated
+        );
+        assert_eq!(
+            damage("a.jpg", &jpeg[..jpeg.len() / 2]),
+            BadImageKind::Truncated
+        );
+
+        // Scrambling the pixel data breaks the PNG's checksums.
+        let mut corrupt = png.clone();
+        let middle = corrupt.len() / 2;
+        corrupt[middle..middle + 8].fill(0xff);
+        assert_eq!(damage("a.png", &corrupt), BadImageKind::Corrupt);
+    }
+
+    #[test]
+    fn missing_extensions_are_only_added_when_allowed() {
+        let png = png();
+        let name = Path::new("photos.zip!noext");
+        let verdict = Checker::new().check_reader(name, None, &png[..]);
+        assert!(matches!(verdict, Verdict::Error(Error::BadExtension(path)) if path == name));
+
+        let adding = Checker {
+            allow_missing: true,
+            ..Checker::new()
+        };
+        let verdict = adding.check_reader(name, None, &png[..]);
+        assert!(matches!(
+            verdict,
+            Verdict::Mismatch {
+                proposed: "png",
+                ..
+            }
+        ));
+        let verdict = Checker::new().check_reader(name, None, &b"just text"[..]);
+        assert!(matches!(verdict, Verdict::Unknown));
+    }
+
+    #[test]
+    fn formats_without_a_decoder_pass_verification() {
+        let checker = verifier();
+        // No AVIF decoder is built in, so all that can be checked is the extension.
+        let avif = b"\0\0\0\x1cftypavif\0\0\0\0avifmif1miaf";
+        let verdict = checker.check_reader(Path::new("a.avif"), Some("avif".as_ref()), &avif[..]);
+        assert!(matches!(verdict, Verdict::Ok { .. }), "{verdict:?}");
+    }
+
+    #[test]
+    fn canonical_only_accepts_the_preferred_extension() {
+        let jpeg = Format::Image(ImageFormat::Jpeg);
+        let lenient = Checker::new();
+        let canonical = Checker {
+            canonical: true,
+            ..Checker::new()
+        };
+
+        for extension in ["jpg", "JPG", "jpeg"] {
+            let verdict = lenient.verdict(Some(extension.as_ref()), jpeg);
+            assert!(matches!(verdict, Verdict::Ok { .. }), "{extension}");
+        }
+        assert!(matches!(
+            canonical.verdict(Some("jpg".as_ref()), jpeg),
+            Verdict::Ok { .. }
+        ));
+        for extension in ["JPG", "jpeg"] {
+            let verdict = canonical.verdict(Some(extension.as_ref()), jpeg);
+            assert!(
+                matches!(
+                    verdict,
+                    Verdict::Mismatch {
+                        proposed: "jpg",
+                        ..
+                    }
+                ),
+                "{extension}"
+            );
+        }
+    }
+}
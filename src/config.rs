@@ -0,0 +1,247 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{Error, Format, Preferences, Result, HEADER_LEN};
+
+/// A user-defined magic number, declared in the config file.
+///
+/// Signatures are loaded once and live for the rest of the process, which lets
+/// [`Format::Custom`](crate::Format::Custom) stay as cheap to pass around as the built-in formats.
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct Signature {
+    /// The format's name, as shown in output and used with `--prefer`.
+    pub name: &'static str,
+    /// Where in the header `pattern` starts.
+    pub offset: usize,
+    /// The bytes to look for.
+    pub pattern: Vec<u8>,
+    /// Which bits of `pattern` must match; all of them if absent.
+    pub mask: Option<Vec<u8>>,
+    /// Acceptable extensions, preferred first.
+    pub extensions: &'static [&'static str],
+}
+
+impl Signature {
+    /// Whether `header` carries this signature.
+    pub fn matches(&self, header: &[u8]) -> bool {
+        let Some(bytes) = header.get(self.offset..self.offset + self.pattern.len()) else {
+            return false;
+        };
+
+        match &self.mask {
+            Some(mask) => bytes
+                .iter()
+                .zip(&self.pattern)
+                .zip(mask)
+                .all(|((byte, pattern), mask)| byte & mask == pattern & mask),
+            None => bytes == self.pattern,
+        }
+    }
+}
+
+/// Settings read from the imgfix config file.
+#[derive(Debug, Default)]
+pub struct Config {
+    /// Custom formats, in the order they're tried.
+    pub signatures: &'static [Signature],
+    /// Extensions preferred over the built-in ones.
+    pub preferences: Preferences,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    #[serde(default)]
+    signature: Vec<RawSignature>,
+    /// format name to preferred extension
+    #[serde(default)]
+    prefer: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSignature {
+    name: String,
+    #[serde(default)]
+    offset: usize,
+    pattern: String,
+    mask: Option<String>,
+    extensions: Vec<String>,
+    preferred: Option<String>,
+}
+
+impl Config {
+    /// `$IMGFIX_CONFIG`, or `imgfix/config.toml` under the user's config directory.
+    pub fn default_path() -> Option<PathBuf> {
+        if let Some(path) = env::var_os("IMGFIX_CONFIG") {
+            return Some(path.into());
+        }
+
+        let base = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(base.join("imgfix").join("config.toml"))
+    }
+
+    /// Loads and validates a config file, reporting every problem found.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).map_err(|e| Error::from(e).with_path(path))?;
+        let raw: RawConfig =
+            toml::from_str(&text).map_err(|e| Error::config(path, vec![e.to_string()]))?;
+
+        let mut problems = Vec::new();
+        let mut names = HashSet::new();
+        let mut signatures = Vec::new();
+        for raw in raw.signature {
+            if !names.insert(raw.name.clone()) {
+                problems.push(format!("signature {}: defined more than once", raw.name));
+            }
+            match raw.build() {
+                Ok(signature) => signatures.push(signature),
+                Err(problem) => problems.push(format!("signature {}: {problem}", raw.name)),
+            }
+        }
+
+        let signatures = Vec::leak(signatures);
+        let mut preferences = Preferences::default();
+        for (name, extension) in &raw.prefer {
+            let result = match Format::from_name(name, signatures) {
+                Some(format) => preferences.set(format, extension),
+                None => Err("no such format".into()),
+            };
+            if let Err(problem) = result {
+                problems.push(format!("prefer {name}: {problem}"));
+            }
+        }
+
+        if !problems.is_empty() {
+            return Err(Error::config(path, problems));
+        }
+
+        Ok(Config {
+            signatures,
+            preferences,
+        })
+    }
+}
+
+impl RawSignature {
+    fn build(&self) -> Result<Signature, String> {
+        let pattern = parse_hex(&self.pattern).map_err(|e| format!("pattern: {e}"))?;
+        if pattern.is_empty() {
+            return Err("pattern is empty".into());
+        }
+        if self.offset + pattern.len() > HEADER_LEN {
+            return Err(format!(
+                "pattern ends past the {HEADER_LEN} bytes read from each file"
+            ));
+        }
+
+        let mask = match &self.mask {
+            Some(mask) => {
+                let mask = parse_hex(mask).map_err(|e| format!("mask: {e}"))?;
+                if mask.len() != pattern.len() {
+                    return Err("mask and pattern differ in length".into());
+                }
+                Some(mask)
+            }
+            None => None,
+        };
+
+        let mut extensions: Vec<_> = self
+            .extensions
+            .iter()
+            .map(|ext| ext.trim_start_matches('.').to_ascii_lowercase())
+            .collect();
+        if extensions.is_empty() || extensions.iter().any(|ext| ext.is_empty()) {
+            return Err("extensions must be a list of non-empty names".into());
+        }
+
+        if let Some(preferred) = &self.preferred {
+            let preferred = preferred.trim_start_matches('.').to_ascii_lowercase();
+            let idx = extensions
+                .iter()
+                .position(|ext| *ext == preferred)
+                .ok_or_else(|| format!("preferred extension {preferred} is not in extensions"))?;
+            extensions[..=idx].rotate_right(1);
+        }
+
+        let extensions = extensions.into_iter().map(leak).collect();
+        Ok(Signature {
+            name: leak(self.name.clone()),
+            offset: self.offset,
+            pattern,
+            mask,
+            extensions: Vec::leak(extensions),
+        })
+    }
+}
+
+/// Parses hex bytes such as `44 44 53 20` or `0x44445320`.
+fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    let digits: String = text
+        .trim()
+        .trim_start_matches("0x")
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+
+    if !digits.is_ascii() {
+        return Err("not hex".into());
+    }
+    if !digits.len().is_multiple_of(2) {
+        return Err("odd number of hex digits".into());
+    }
+
+    (0..digits.len())
+        .step_by(2)
+        .map(|idx| {
+            u8::from_str_radix(&digits[idx..idx + 2], 16)
+                .map_err(|_| format!("not hex: {}", &digits[idx..idx + 2]))
+        })
+        .collect()
+}
+
+fn leak(text: String) -> &'static str {
+    Box::leak(text.into_boxed_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RawSignature;
+
+    fn raw(pattern: &str, mask: Option<&str>, preferred: Option<&str>) -> RawSignature {
+        RawSignature {
+            name: "test".into(),
+            offset: 2,
+            pattern: pattern.into(),
+            mask: mask.map(Into::into),
+            extensions: vec!["ktx".into(), ".KTX2".into()],
+            preferred: preferred.map(Into::into),
+        }
+    }
+
+    #[test]
+    fn signature_matches_under_mask() {
+        let signature = raw("ab 4b f0", Some("ff ff f0"), Some("ktx2"))
+            .build()
+            .unwrap();
+        assert_eq!(signature.extensions, ["ktx2", "ktx"]);
+        assert!(signature.matches(b"..\xab\x4b\xf7"));
+        assert!(!signature.matches(b"..\xab\x4b\x07"));
+        assert!(!signature.matches(b"..\xab\x4b"));
+    }
+
+    #[test]
+    fn bad_signatures_are_rejected() {
+        assert!(raw("abc", None, None).build().is_err());
+        assert!(raw("zz", None, None).build().is_err());
+        assert!(raw("ab cd", Some("ff"), None).build().is_err());
+        assert!(raw("ab", None, Some("png")).build().is_err());
+    }
+}
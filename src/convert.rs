@@ -0,0 +1,199 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use image::{
+    codecs::png::{self, PngEncoder},
+    ColorType, DynamicImage, ImageEncoder, ImageFormat, ImageOutputFormat,
+};
+
+use crate::{rename, Error, Format, Preserve, Result};
+
+/// How hard to compress PNG output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum PngCompression {
+    /// Quick to write, but larger.
+    Fast,
+    /// A balance of size and speed.
+    #[default]
+    Default,
+    /// Smallest, but slow to write.
+    Best,
+}
+
+/// Re-encodes files into the format their extension claims.
+///
+/// Unlike renames, conversions aren't journaled; keep a backup if they might need undoing.
+#[derive(Clone, Copy, Debug)]
+pub struct Converter {
+    /// 1 to 100.
+    pub jpeg_quality: u8,
+    /// How hard to compress PNG output.
+    pub png_compression: PngCompression,
+    /// Copy the original to `<name>.bak` before replacing it.
+    pub backup: bool,
+    /// Metadata the converted file (and any backup) takes from the original.
+    pub preserve: Preserve,
+}
+
+/// What a conversion did.
+#[derive(Debug)]
+pub struct Converted {
+    /// The format the file was re-encoded into.
+    pub to: ImageFormat,
+    /// Where the original was copied, if anywhere.
+    pub backup: Option<PathBuf>,
+}
+
+impl Default for Converter {
+    fn default() -> Self {
+        Converter {
+            jpeg_quality: 90,
+            png_compression: PngCompression::Default,
+            backup: false,
+            preserve: Preserve::default(),
+        }
+    }
+}
+
+impl Converter {
+    /// The format `path` would be converted into, if its contents and extension allow it.
+    pub fn target(path: &Path, detected: Format) -> Option<ImageFormat> {
+        detected.image_format()?;
+        let to = ImageFormat::from_path(path).ok()?;
+        to.can_write().then_some(to)
+    }
+
+    /// Decodes `path` as `detected` and re-encodes it in place into the format its extension
+    /// names.
+    ///
+    /// The new contents go to a temporary file beside the original, which is only replaced
+    /// once they've been written in full.
+    pub fn convert(&self, path: &Path, detected: Format) -> Result<Converted> {
+        let to = Self::target(path, detected).ok_or_else(|| Error::unconvertible(path))?;
+        let from = detected.image_format().unwrap();
+
+        let file = File::open(path).map_err(|e| Error::from(e).with_path(path))?;
+        let image = image::load(BufReader::new(file), from)
+            .map_err(|e| Error::damaged_image(path, detected, e))?;
+
+        let mut backup = None;
+        rename::replace_file(path, self.preserve, |file| {
+            self.write(&image, to, path, file)?;
+            if self.backup {
+                backup = Some(self.backup(path)?);
+            }
+            Ok(())
+        })
+        .map_err(|e| e.with_path(path))?;
+
+        Ok(Converted { to, backup })
+    }
+
+    fn write(
+        &self,
+        image: &DynamicImage,
+        format: ImageFormat,
+        path: &Path,
+        file: &mut File,
+    ) -> Result<()> {
+        let mut out = BufWriter::new(file);
+
+        let written = match format {
+            ImageFormat::Png => {
+                let compression = match self.png_compression {
+                    PngCompression::Fast => png::CompressionType::Fast,
+                    PngCompression::Default => png::CompressionType::Default,
+                    PngCompression::Best => png::CompressionType::Best,
+                };
+                // PNG has no floating point samples.
+                let image = match image.color() {
+                    ColorType::Rgb32F | ColorType::Rgba32F => image.to_rgba16().into(),
+                    _ => image.clone(),
+                };
+                PngEncoder::new_with_quality(&mut out, compression, png::FilterType::Adaptive)
+                    .write_image(
+                        image.as_bytes(),
+                        image.width(),
+                        image.height(),
+                        image.color(),
+                    )
+            }
+            ImageFormat::Jpeg => {
+                // JPEG only holds 8-bit grey or RGB samples, without alpha.
+                let image: DynamicImage = match image.color().has_color() {
+                    true => image.to_rgb8().into(),
+                    false => image.to_luma8().into(),
+                };
+                image.write_to(&mut out, ImageOutputFormat::Jpeg(self.jpeg_quality))
+            }
+            format => image.write_to(&mut out, format),
+        };
+        written.map_err(|e| Error::unencodable(path, e))?;
+
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Copies `path` to `<name>.bak`, or a free variant of it.
+    fn backup(&self, path: &Path) -> Result<PathBuf> {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".bak");
+        let mut backup = PathBuf::from(name);
+        if rename::exists(&backup)? {
+            backup = rename::free_name(&backup)?;
+        }
+
+        fs::copy(path, &backup)?;
+        self.preserve.copy(path, &backup)?;
+        Ok(backup)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use image::{ImageBuffer, ImageFormat, Rgba};
+
+    use super::{Converter, PngCompression};
+    use crate::{Format, Preserve};
+
+    #[test]
+    fn target_comes_from_the_extension() {
+        let png = Format::Image(ImageFormat::Png);
+        let target = |name| Converter::target(Path::new(name), png);
+        assert_eq!(target("thumb.jpg"), Some(ImageFormat::Jpeg));
+        assert_eq!(target("thumb.PNG"), Some(ImageFormat::Png));
+        assert_eq!(target("thumb.txt"), None);
+        assert_eq!(target("thumb"), None);
+        assert_eq!(Converter::target(Path::new("a.jpg"), Format::Heic), None);
+    }
+
+    #[test]
+    fn deep_images_are_converted_to_jpeg() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("deep.jpg");
+        let image = ImageBuffer::from_fn(8, 8, |x, y| {
+            Rgba([x as u16 * 8000, y as u16 * 8000, 0, 40_000])
+        });
+        image.save_with_format(&path, ImageFormat::Png).unwrap();
+
+        let converter = Converter {
+            jpeg_quality: 90,
+            png_compression: PngCompression::Default,
+            backup: false,
+            preserve: Preserve::default(),
+        };
+        let converted = converter
+            .convert(&path, Format::Image(ImageFormat::Png))
+            .unwrap();
+        assert_eq!(converted.to, ImageFormat::Jpeg);
+        let jpeg =
+            image::load_from_memory_with_format(&fs::read(&path).unwrap(), ImageFormat::Jpeg);
+        assert_eq!(jpeg.unwrap().width(), 8);
+    }
+}
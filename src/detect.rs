@@ -0,0 +1,380 @@
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+use uncased::UncasedStr;
+
+use crate::{Error, Format, Result};
+
+/// Number of leading bytes read from each file for format detection.
+///
+/// This must cover the longest signature known to `image::guess_format`, as well as the first
+/// TIFF directory of camera RAW files and any XML prolog ahead of an `<svg>` element.
+pub const HEADER_LEN: usize = 4096;
+
+/// The extension a file of this format should be given.
+pub fn preferred_extension(format: Format) -> &'static str {
+    format.extensions()[0]
+}
+
+/// Whether `extension` is acceptable for `format`, ignoring case.
+pub fn is_allowed_extension(extension: &OsStr, format: Format) -> bool {
+    let Some(extension) = extension.to_str() else {
+        return false;
+    };
+    let extension: &UncasedStr = extension.into();
+    format.extensions().iter().any(|&ext| ext == extension)
+}
+
+/// Guesses the format of a file from its leading bytes.
+pub fn guess_format(path: &Path) -> Result<Format> {
+    let buffer = read_header(File::open(path)?)?;
+    let format = detect_format(&buffer).map_err(|e| Error::bad_image(path, e))?;
+    Ok(format)
+}
+
+/// Guesses a format from a file header, trying imgfix's own signatures before `image`'s.
+pub fn detect_format(header: &[u8]) -> image::ImageResult<Format> {
+    let sniffed = sniff_ftyp(header)
+        .or_else(|| sniff_jpeg_xl(header))
+        .or_else(|| sniff_psd(header))
+        .or_else(|| sniff_raw(header))
+        .or_else(|| sniff_svg(header));
+
+    match sniffed {
+        Some(format) => Ok(format),
+        None => image::guess_format(header).map(Format::Image),
+    }
+}
+
+/// Reads at most [`HEADER_LEN`] bytes from the start of `reader`.
+pub fn read_header(reader: impl Read) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(HEADER_LEN);
+    reader.take(HEADER_LEN as u64).read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// Reads the extension from the file name alone; dotfiles and trailing dots don't count.
+pub fn read_extension(path: &Path) -> Option<&OsStr> {
+    path.extension().filter(|extension| !extension.is_empty())
+}
+
+/// ISO base media files (HEIF, AVIF) name their flavor in the brands of the leading `ftyp` box.
+fn sniff_ftyp(header: &[u8]) -> Option<Format> {
+    if header.get(4..8)? != b"ftyp" {
+        return None;
+    }
+
+    let size = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+    let major = header.get(8..12)?;
+    let compatible = header
+        .get(16..size.min(header.len()))
+        .unwrap_or_default()
+        .chunks_exact(4);
+
+    const HEVC: &[&[u8]] = &[
+        b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"hevm", b"hevs",
+    ];
+    const AVIF: &[&[u8]] = &[b"avif", b"avis"];
+    const HEIF: &[&[u8]] = &[b"mif1", b"msf1"];
+
+    if AVIF.contains(&major) {
+        return Some(Format::Image(image::ImageFormat::Avif));
+    }
+    if HEVC.contains(&major) {
+        return Some(Format::Heic);
+    }
+    if !HEIF.contains(&major) {
+        return None;
+    }
+
+    // Generic HEIF major brands defer to whatever codec brands follow.
+    let mut format = Format::Heif;
+    for brand in compatible {
+        if AVIF.contains(&brand) {
+            return Some(Format::Image(image::ImageFormat::Avif));
+        }
+        if HEVC.contains(&brand) {
+            format = Format::Heic;
+        }
+    }
+    Some(format)
+}
+
+fn sniff_jpeg_xl(header: &[u8]) -> Option<Format> {
+    const CODESTREAM: &[u8] = &[0xff, 0x0a];
+    const CONTAINER: &[u8] = b"\0\0\0\x0cJXL \r\n\x87\n";
+
+    (header.starts_with(CODESTREAM) || header.starts_with(CONTAINER)).then_some(Format::JpegXl)
+}
+
+fn sniff_psd(header: &[u8]) -> Option<Format> {
+    header.starts_with(b"8BPS").then_some(Format::Psd)
+}
+
+/// Camera RAW formats are mostly TIFF underneath; tell them apart by the first directory.
+fn sniff_raw(header: &[u8]) -> Option<Format> {
+    let big_endian = match header.get(..4)? {
+        b"II*\0" => false,
+        b"MM\0*" => true,
+        _ => return None,
+    };
+
+    if header.get(8..11) == Some(b"CR\x02") {
+        return Some(Format::Cr2);
+    }
+
+    let u16_at = |at: usize| {
+        let bytes = header.get(at..at + 2)?.try_into().unwrap();
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let u32_at = |at: usize| {
+        let bytes = header.get(at..at + 4)?.try_into().unwrap();
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+
+    const NEW_SUBFILE_TYPE: u16 = 0x00fe;
+    const MAKE: u16 = 0x010f;
+    const SUB_IFDS: u16 = 0x014a;
+    const DNG_VERSION: u16 = 0xc612;
+    const SONY_PRIVATE: u16 = 0xc634;
+
+    let ifd = u32_at(4)? as usize;
+    let mut make = None;
+    // Software exporting a plain TIFF keeps the camera's make, so only trust it alongside the
+    // layout cameras write: a thumbnail first, with the sensor data in a sub-IFD.
+    let mut raw_layout = false;
+    for idx in 0..u16_at(ifd)? as usize {
+        let entry = ifd + 2 + idx * 12;
+        match u16_at(entry) {
+            Some(DNG_VERSION) => return Some(Format::Dng),
+            Some(SUB_IFDS | SONY_PRIVATE) => raw_layout = true,
+            Some(NEW_SUBFILE_TYPE) => raw_layout |= u32_at(entry + 8)? & 1 == 1,
+            Some(MAKE) => {
+                let len = u32_at(entry + 4)? as usize;
+                let at = if len <= 4 {
+                    entry + 8
+                } else {
+                    u32_at(entry + 8)? as usize
+                };
+                make = header.get(at..at + len);
+            }
+            Some(_) => {}
+            None => break,
+        }
+    }
+
+    match make.filter(|_| raw_layout)? {
+        make if make.starts_with(b"NIKON") => Some(Format::Nef),
+        make if make.starts_with(b"SONY") => Some(Format::Arw),
+        _ => None,
+    }
+}
+
+/// SVG is XML, so look past any prolog, comments and doctype for an `<svg` root element.
+fn sniff_svg(header: &[u8]) -> Option<Format> {
+    let mut text = header.strip_prefix(b"\xef\xbb\xbf").unwrap_or(header);
+
+    loop {
+        text = text.trim_ascii_start();
+        let skip_to = |end: &[u8]| {
+            text.windows(end.len())
+                .position(|window| window == end)
+                .map(|at| &text[at + end.len()..])
+        };
+
+        text = if text.starts_with(b"<?") {
+            skip_to(b"?>")?
+        } else if text.starts_with(b"<!--") {
+            skip_to(b"-->")?
+        } else if text.starts_with(b"<!DOCTYPE") {
+            // Only an internal subset, opened before the doctype ends, can hold `>`s of its own.
+            match text.iter().find(|&&byte| byte == b'[' || byte == b'>')? {
+                b'[' => skip_to(b"]>")?,
+                _ => skip_to(b">")?,
+            }
+        } else {
+            break;
+        };
+    }
+
+    let rest = text.strip_prefix(b"<svg")?;
+    matches!(
+        rest.first(),
+        Some(b' ' | b'\t' | b'\r' | b'\n' | b'>' | b'/' | b':')
+    )
+    .then_some(Format::Svg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, path::Path};
+
+    use image::ImageFormat;
+
+    use super::{detect_format, read_extension, HEADER_LEN};
+    use crate::Format;
+
+    // Mirrors the signature table behind `image::guess_format`.
+    static SIGNATURES: &[(&[u8], ImageFormat)] = &[
+        (b"\x89PNG\r\n\x1a\n", ImageFormat::Png),
+        (&[0xff, 0xd8, 0xff], ImageFormat::Jpeg),
+        (b"GIF89a", ImageFormat::Gif),
+        (b"GIF87a", ImageFormat::Gif),
+        (b"RIFF", ImageFormat::WebP),
+        (b"MM\x00*", ImageFormat::Tiff),
+        (b"II*\x00", ImageFormat::Tiff),
+        (b"DDS ", ImageFormat::Dds),
+        (b"BM", ImageFormat::Bmp),
+        (&[0, 0, 1, 0], ImageFormat::Ico),
+        (b"#?RADIANCE", ImageFormat::Hdr),
+        (b"P1", ImageFormat::Pnm),
+        (b"P2", ImageFormat::Pnm),
+        (b"P3", ImageFormat::Pnm),
+        (b"P4", ImageFormat::Pnm),
+        (b"P5", ImageFormat::Pnm),
+        (b"P6", ImageFormat::Pnm),
+        (b"P7", ImageFormat::Pnm),
+        (b"farbfeld", ImageFormat::Farbfeld),
+        (b"\0\0\0 ftypavif", ImageFormat::Avif),
+        (b"\0\0\0\x1cftypavif", ImageFormat::Avif),
+        (&[0x76, 0x2f, 0x31, 0x01], ImageFormat::OpenExr),
+    ];
+
+    #[test]
+    fn header_covers_every_signature() {
+        for &(signature, format) in SIGNATURES {
+            assert!(
+                signature.len() <= HEADER_LEN,
+                "{format:?} signature too long"
+            );
+
+            let mut header = signature.to_vec();
+            header.resize(HEADER_LEN, 0xaa);
+            assert_eq!(detect_format(&header).unwrap(), Format::Image(format));
+        }
+    }
+
+    /// A little-endian TIFF header whose first directory holds `entries`, followed by `data`.
+    fn tiff(entries: &[(u16, u16, u32, u32)], data: &[u8]) -> Vec<u8> {
+        let mut header = b"II*\0\x08\0\0\0".to_vec();
+        header.extend((entries.len() as u16).to_le_bytes());
+        for &(tag, kind, count, value) in entries {
+            header.extend(tag.to_le_bytes());
+            header.extend(kind.to_le_bytes());
+            header.extend(count.to_le_bytes());
+            header.extend(value.to_le_bytes());
+        }
+        header.extend(0u32.to_le_bytes());
+        header.extend(data);
+        header
+    }
+
+    #[test]
+    fn detects_formats_image_does_not() {
+        let make_at = 8 + 2 + 3 * 12 + 4;
+        let cases: Vec<(Vec<u8>, Format)> = vec![
+            (b"\0\0\0\x18ftypheic\0\0\0\0mif1heic".to_vec(), Format::Heic),
+            (b"\0\0\0\x18ftypmif1\0\0\0\0mif1heic".to_vec(), Format::Heic),
+            (b"\0\0\0\x14ftypmif1\0\0\0\0mif1".to_vec(), Format::Heif),
+            (
+                b"\0\0\0\x18ftypmif1\0\0\0\0mif1avif".to_vec(),
+                Format::Image(ImageFormat::Avif),
+            ),
+            (
+                b"\0\0\0\x14ftypavis\0\0\0\0avis".to_vec(),
+                Format::Image(ImageFormat::Avif),
+            ),
+            (vec![0xff, 0x0a, 0xfa], Format::JpegXl),
+            (b"\0\0\0\x0cJXL \r\n\x87\n".to_vec(), Format::JpegXl),
+            (b"<svg xmlns='http://www.w3.org/2000/svg'/>".to_vec(), Format::Svg),
+            (
+                b"\xef\xbb\xbf<?xml version='1.0'?>\n<!-- hi -->\n<!DOCTYPE svg [ <!ENTITY x 'y'> ]>\n<svg>"
+                    .to_vec(),
+                Format::Svg,
+            ),
+            (
+                b"<!DOCTYPE svg PUBLIC \"-//W3C//DTD SVG 1.1//EN\" \"http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd\">\n<svg><style><![CDATA[a > b {}]]></style></svg>"
+                    .to_vec(),
+                Format::Svg,
+            ),
+            (b"8BPS\0\x01".to_vec(), Format::Psd),
+            (b"II*\0\x10\0\0\0CR\x02\0".to_vec(), Format::Cr2),
+            (tiff(&[(0x0100, 4, 1, 1), (0xc612, 1, 4, 0x0104)], &[]), Format::Dng),
+            (
+                tiff(
+                    &[(0x00fe, 4, 1, 1), (0x010f, 2, 18, make_at), (0x014a, 4, 1, 0x200)],
+                    b"NIKON CORPORATION\0",
+                ),
+                Format::Nef,
+            ),
+            (
+                tiff(
+                    &[(0x0100, 4, 1, 1), (0x010f, 2, 5, make_at), (0xc634, 1, 4, 0x200)],
+                    b"SONY\0",
+                ),
+                Format::Arw,
+            ),
+            // A TIFF exported by the camera maker's software
+            (
+                tiff(
+                    &[(0x00fe, 4, 1, 0), (0x0100, 4, 1, 1), (0x010f, 2, 18, make_at)],
+                    b"NIKON CORPORATION\0",
+                ),
+                Format::Image(ImageFormat::Tiff),
+            ),
+            (
+                tiff(
+                    &[(0x0100, 4, 1, 1), (0x0101, 4, 1, 1), (0x010f, 2, 6, make_at)],
+                    b"Canon\0",
+                ),
+                Format::Image(ImageFormat::Tiff),
+            ),
+        ];
+
+        for (header, format) in cases {
+            assert!(header.len() <= HEADER_LEN);
+            assert_eq!(detect_format(&header).unwrap(), format, "{header:?}");
+        }
+
+        assert!(detect_format(b"<html><svg></svg></html>").is_err());
+        assert!(detect_format(b"\0\0\0\x14ftypisom\0\0\0\0mp41").is_err());
+    }
+
+    #[test]
+    fn extension_comes_from_file_name() {
+        let cases = [
+            ("photo.jpg", Some("jpg")),
+            ("IMG.JPG", Some("JPG")),
+            ("./photo.png", Some("png")),
+            ("../foo", None),
+            ("./photos.d/IMG_1", None),
+            ("photos.d/IMG_1.gif", Some("gif")),
+            ("/abs/dir.x/photo.webp", Some("webp")),
+            ("photo.jpg.png", Some("png")),
+            (".hidden", None),
+            (".hidden.png", Some("png")),
+            ("dir/.hidden", None),
+            ("photo.", None),
+            ("photo..", None),
+            ("image", None),
+            ("..", None),
+        ];
+
+        for (path, expected) in cases {
+            let extension = read_extension(Path::new(path)).and_then(OsStr::to_str);
+            assert_eq!(extension, expected, "{path}");
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    error, fmt, io,
+    path::{Path, PathBuf},
+};
+
+use image::ImageError;
+
+use crate::Format;
+
+/// A result whose error defaults to [`Error`].
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Anything that can go wrong checking, fixing or undoing a file.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// An image that couldn't be recognized or decoded.
+    #[error(transparent)]
+    Image(Box<BadImage>),
+
+    /// An I/O error not tied to any one path.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    /// An I/O error reading or writing `path`.
+    #[error("{source} ({})", .path.display())]
+    Access {
+        /// The file or directory being accessed.
+        path: PathBuf,
+        /// What went wrong.
+        source: io::Error,
+    },
+
+    /// An error walking a directory.
+    #[error(transparent)]
+    Walk(#[from] walkdir::Error),
+
+    /// The file has no extension to check, and missing ones aren't allowed.
+    #[error("no usable extension: {}", .0.display())]
+    BadExtension(PathBuf),
+
+    /// The name a file would be renamed to is taken by another file.
+    #[error("target already exists: {}", .0.display())]
+    Conflict(PathBuf),
+
+    /// A renamed file was modified after the rename, so undoing it could lose the changes.
+    #[error("file has changed since it was renamed: {}", .0.display())]
+    Changed(PathBuf),
+
+    /// A file copied to another filesystem read back differently from the original.
+    #[error("copy does not match the original: {}", .0.display())]
+    CopyMismatch(PathBuf),
+
+    /// A journal line that isn't a valid entry.
+    #[error("bad journal entry: {0}")]
+    Journal(#[from] serde_json::Error),
+
+    /// A configuration file with one or more problems, each described in `problems`.
+    #[error("{}:\n  {}", .path.display(), .problems.join("\n  "))]
+    Config {
+        /// The config file.
+        path: PathBuf,
+        /// Each problem found, one per line when shown.
+        problems: Vec<String>,
+    },
+
+    /// The contents can't be decoded, or the extension names no format `image` can encode.
+    #[error("can't convert to the format its extension names: {}", .0.display())]
+    Unconvertible(PathBuf),
+
+    /// A converted image that couldn't be encoded in its new format.
+    #[error("can't encode the converted image: {source} ({})", .path.display())]
+    Unencodable {
+        /// The file being converted.
+        path: PathBuf,
+        /// The error `image` reported.
+        source: ImageError,
+    },
+
+    /// An archive that couldn't be read or rewritten.
+    #[error("bad archive: {message} ({})", .path.display())]
+    Archive {
+        /// The archive.
+        path: PathBuf,
+        /// What was wrong with it.
+        message: String,
+    },
+
+    /// An extension preference naming an unknown format or extension.
+    #[error("bad preference: {0}")]
+    Preference(String),
+
+    /// A directory named on the command line while not walking recursively.
+    #[error("skipping directory {} (use --recursive)", .0.display())]
+    SkippedDirectory(PathBuf),
+}
+
+impl Error {
+    /// Attaches the path being processed to a bare I/O error.
+    pub(crate) fn with_path(self, path: &Path) -> Self {
+        match self {
+            Error::Io(source) => Error::Access {
+                path: path.into(),
+                source,
+            },
+            e => e,
+        }
+    }
+
+    pub(crate) fn bad_extension(path: impl Into<PathBuf>) -> Self {
+        Error::BadExtension(path.into())
+    }
+
+    pub(crate) fn conflict(path: impl Into<PathBuf>) -> Self {
+        Error::Conflict(path.into())
+    }
+
+    pub(crate) fn changed(path: impl Into<PathBuf>) -> Self {
+        Error::Changed(path.into())
+    }
+
+    pub(crate) fn copy_mismatch(path: impl Into<PathBuf>) -> Self {
+        Error::CopyMismatch(path.into())
+    }
+
+    pub(crate) fn unconvertible(path: impl Into<PathBuf>) -> Self {
+        Error::Unconvertible(path.into())
+    }
+
+    pub(crate) fn unencodable(path: impl Into<PathBuf>, source: ImageError) -> Self {
+        Error::Unencodable {
+            path: path.into(),
+            source,
+        }
+    }
+
+    pub(crate) fn archive(path: impl Into<PathBuf>, error: zip::result::ZipError) -> Self {
+        match error {
+            zip::result::ZipError::Io(e) => Error::Io(e).with_path(&path.into()),
+            e => Error::Archive {
+                path: path.into(),
+                message: e.to_string(),
+            },
+        }
+    }
+
+    pub(crate) fn config(path: impl Into<PathBuf>, problems: Vec<String>) -> Self {
+        Error::Config {
+            path: path.into(),
+            problems,
+        }
+    }
+
+    pub(crate) fn bad_image(path: impl Into<PathBuf>, error: ImageError) -> Self {
+        Error::Image(Box::new(BadImage {
+            path: path.into(),
+            kind: BadImageKind::Unrecognized,
+            format: None,
+            proposed: None,
+            error,
+        }))
+    }
+
+    /// An image that was recognized as `format` but failed to decode.
+    pub(crate) fn damaged_image(
+        path: impl Into<PathBuf>,
+        format: Format,
+        error: ImageError,
+    ) -> Self {
+        Error::Image(Box::new(BadImage {
+            path: path.into(),
+            kind: BadImageKind::classify(&error),
+            format: Some(format),
+            proposed: None,
+            error,
+        }))
+    }
+}
+
+/// An image whose contents could not be recognized or decoded.
+#[derive(Debug)]
+pub struct BadImage {
+    /// The file the image was read from.
+    pub path: PathBuf,
+    /// What was wrong with it.
+    pub kind: BadImageKind,
+    /// The format detected from the header, if any.
+    pub format: Option<Format>,
+    /// The extension the file should have, if its name doesn't suit `format` either.
+    pub proposed: Option<&'static str>,
+    /// The error `image` reported.
+    pub error: ImageError,
+}
+
+/// Why a [`BadImage`] couldn't be used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BadImageKind {
+    /// The header matches no known format.
+    Unrecognized,
+    /// The data ends early.
+    Truncated,
+    /// The data is malformed.
+    Corrupt,
+    /// The image is well formed but uses features the decoder doesn't support.
+    Undecodable,
+}
+
+impl BadImageKind {
+    fn classify(error: &ImageError) -> Self {
+        match error {
+            ImageError::IoError(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                BadImageKind::Truncated
+            }
+            // Not every decoder reports running out of data as an I/O error.
+            ImageError::Decoding(e) => {
+                let message = e.to_string().to_ascii_lowercase();
+                if message.contains("eof") || message.contains("unexpected end") {
+                    BadImageKind::Truncated
+                } else {
+                    BadImageKind::Corrupt
+                }
+            }
+            ImageError::IoError(_) => BadImageKind::Corrupt,
+            _ => BadImageKind::Undecodable,
+        }
+    }
+}
+
+impl fmt::Display for BadImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            BadImageKind::Unrecognized => "",
+            BadImageKind::Truncated => "truncated",
+            BadImageKind::Corrupt => "corrupt",
+            BadImageKind::Undecodable => "undecodable",
+        };
+
+        match self.format {
+            Some(format) if !kind.is_empty() => write!(f, "{kind} {format} image: ")?,
+            _ => {}
+        }
+        write!(f, "{} ({})", self.error, self.path.display())
+    }
+}
+
+impl BadImage {
+    /// Whether the image was recognized but failed to decode.
+    pub fn is_damaged(&self) -> bool {
+        self.kind != BadImageKind::Unrecognized
+    }
+}
+
+impl error::Error for BadImage {}
@@ -0,0 +1,236 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    journal::Journal,
+    rename::{self, Conflict, Renamed},
+    sidecar, Error, Format, Preserve, Result,
+};
+
+/// Renames files to the extension their contents call for.
+#[derive(Debug, Default)]
+pub struct Fixer {
+    /// What to do when the corrected name is already taken.
+    pub conflict: Conflict,
+    /// Where to record each rename, if anywhere.
+    pub journal: Option<Journal>,
+    /// Extensions of sidecar files to rename along with their image.
+    pub sidecars: Vec<String>,
+    /// Where to put corrected files instead of renaming them where they are.
+    pub output: Option<OutputDir>,
+    /// What to carry over when a file is copied, including moves to another filesystem.
+    pub preserve: Preserve,
+}
+
+/// A tree that corrected files are moved or copied into, keeping their place relative to the
+/// directory they were found in.
+#[derive(Clone, Debug)]
+pub struct OutputDir {
+    /// The root of the tree.
+    pub dir: PathBuf,
+    /// Leave the originals where they are.
+    pub copy: bool,
+}
+
+impl OutputDir {
+    /// Where a file at `relative`, corrected to `proposed`, ends up.
+    pub fn target(&self, relative: &Path, proposed: &str) -> PathBuf {
+        // Only normal components, so that nothing lands outside the tree.
+        let relative: PathBuf = relative
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
+        self.dir.join(relative).with_extension(proposed)
+    }
+}
+
+/// What became of a file and its sidecars.
+#[derive(Debug)]
+pub struct Fixed {
+    /// What became of the file itself.
+    pub renamed: Renamed,
+    /// Each sidecar found, and what became of it; these are never overwritten.
+    pub sidecars: Vec<(PathBuf, Result<Renamed>)>,
+}
+
+impl Fixer {
+    /// A fixer that renames in place, handling taken names by `conflict`, with no journal and
+    /// no sidecars.
+    pub fn new(conflict: Conflict) -> Self {
+        Fixer {
+            conflict,
+            journal: None,
+            sidecars: Vec::new(),
+            output: None,
+            preserve: Preserve::default(),
+        }
+    }
+
+    /// Where `path` goes once corrected to `proposed`; `relative` is its path under the
+    /// directory it was found in.
+    pub fn target(&self, path: &Path, relative: &Path, proposed: &str) -> PathBuf {
+        match &self.output {
+            Some(output) => output.target(relative, proposed),
+            None => path.with_extension(proposed),
+        }
+    }
+
+    /// Gives `path` the extension `proposed`, taking its sidecars along and recording the
+    /// renames if they happened.
+    ///
+    /// With an output directory, the file is moved or copied there instead. Copies aren't
+    /// journaled, since the originals are untouched.
+    pub fn fix(
+        &mut self,
+        path: &Path,
+        relative: &Path,
+        detected: Format,
+        proposed: &str,
+    ) -> Result<Fixed> {
+        let to = self.target(path, relative, proposed);
+        if let Some(dir) = to.parent().filter(|_| self.output.is_some()) {
+            fs::create_dir_all(dir).map_err(|e| Error::from(e).with_path(dir))?;
+        }
+        self.relocate(path, to, detected)
+    }
+
+    /// Moves `path` to `to`, which may be in another directory, as [`Fixer::fix`] would; this
+    /// is how `imgfix sort` files images away.
+    pub fn sort(&mut self, path: &Path, to: PathBuf, detected: Format) -> Result<Fixed> {
+        if let Some(dir) = to.parent() {
+            fs::create_dir_all(dir).map_err(|e| Error::from(e).with_path(dir))?;
+        }
+        self.relocate(path, to, detected)
+    }
+
+    fn relocate(&mut self, path: &Path, to: PathBuf, detected: Format) -> Result<Fixed> {
+        let found = sidecar::find(path, &self.sidecars).map_err(|e| e.with_path(path))?;
+        let renamed = self
+            .place(path, to, self.conflict)
+            .map_err(|e| e.with_path(path))?;
+        let Some(to) = renamed.target() else {
+            return Ok(Fixed {
+                renamed,
+                sidecars: Vec::new(),
+            });
+        };
+
+        let mut sidecars = Vec::new();
+        for from in found {
+            if let Some(target) = sidecar::target(&from, path, to) {
+                let moved = self
+                    .place(&from, target, Conflict::Skip)
+                    .map_err(|e| e.with_path(&from));
+                sidecars.push((from, moved));
+            }
+        }
+
+        let copied = self.output.as_ref().is_some_and(|output| output.copy);
+        if let (Some(journal), false) = (&mut self.journal, copied) {
+            let moved: Vec<_> = sidecars
+                .iter()
+                .filter_map(|(from, moved)| {
+                    let to = moved.as_ref().ok()?.target()?;
+                    Some((from.clone(), to.to_owned()))
+                })
+                .collect();
+            journal.record(path, to, detected, &moved)?;
+        }
+
+        Ok(Fixed { renamed, sidecars })
+    }
+
+    fn place(&self, from: &Path, to: PathBuf, conflict: Conflict) -> Result<Renamed> {
+        match &self.output {
+            Some(output) if output.copy => rename::copy(from, to, conflict, self.preserve),
+            _ => rename::rename(from, to, conflict, self.preserve),
+        }
+    }
+
+    /// Moves a damaged image into `dir`, picking a free name if another file got there first.
+    pub fn quarantine(&mut self, path: &Path, dir: &Path, detected: Format) -> Result<Renamed> {
+        fs::create_dir_all(dir).map_err(|e| Error::from(e).with_path(dir))?;
+        let to = dir.join(path.file_name().unwrap_or_default());
+        let moved = rename::rename(path, to, Conflict::Suffix, self.preserve)
+            .map_err(|e| e.with_path(path))?;
+
+        if let (Some(journal), Some(to)) = (&mut self.journal, moved.target()) {
+            journal.record(path, to, detected, &[])?;
+        }
+
+        Ok(moved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use image::ImageFormat;
+
+    use super::{Fixer, OutputDir};
+    use crate::{journal, rename::Conflict, Format};
+
+    #[test]
+    fn files_keep_their_place_under_the_output_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("in/2023/a.png");
+        fs::create_dir_all(from.parent().unwrap()).unwrap();
+        fs::write(&from, b"jpeg").unwrap();
+        fs::write(from.with_extension("xmp"), b"sidecar").unwrap();
+
+        let output = OutputDir {
+            dir: dir.path().join("out"),
+            copy: true,
+        };
+        assert_eq!(
+            output.target(Path::new("../2023/./a.png"), "jpg"),
+            dir.path().join("out/2023/a.jpg")
+        );
+
+        let mut fixer = Fixer::new(Conflict::Skip);
+        fixer.sidecars = vec!["xmp".into()];
+        fixer.output = Some(output);
+        let fixed = fixer
+            .fix(
+                &from,
+                Path::new("2023/a.png"),
+                Format::Image(ImageFormat::Jpeg),
+                "jpg",
+            )
+            .unwrap();
+
+        let to = dir.path().join("out/2023/a.jpg");
+        assert_eq!(fixed.renamed.target(), Some(&*to));
+        assert_eq!(fs::read(&to).unwrap(), b"jpeg");
+        assert_eq!(fs::read(to.with_extension("xmp")).unwrap(), b"sidecar");
+        assert!(from.exists() && from.with_extension("xmp").exists());
+    }
+
+    #[test]
+    fn quarantined_files_never_replace_each_other() {
+        let dir = tempfile::tempdir().unwrap();
+        let quarantine = dir.path().join("quarantine");
+        let png = Format::Image(ImageFormat::Png);
+        let mut fixer = Fixer::new(Conflict::Overwrite);
+        fixer.journal = Some(journal::Journal::new(dir.path().join("imgfix.journal")));
+
+        for (idx, subdir) in ["a", "b"].into_iter().enumerate() {
+            let from = dir.path().join(subdir).join("bad.png");
+            fs::create_dir_all(from.parent().unwrap()).unwrap();
+            fs::write(&from, subdir).unwrap();
+
+            let moved = fixer.quarantine(&from, &quarantine, png).unwrap();
+            let name = ["bad.png", "bad (1).png"][idx];
+            assert_eq!(moved.target(), Some(&*quarantine.join(name)));
+            assert_eq!(fs::read(quarantine.join(name)).unwrap(), subdir.as_bytes());
+            assert!(!from.exists());
+        }
+
+        drop(fixer);
+        let journaled = journal::read(&dir.path().join("imgfix.journal")).unwrap();
+        assert_eq!(journaled.len(), 2);
+    }
+}
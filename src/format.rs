@@ -0,0 +1,115 @@
+use std::fmt;
+
+use image::ImageFormat;
+
+use crate::config::Signature;
+
+/// An image format imgfix knows how to recognize.
+///
+/// Everything `image` can detect is carried as [`Format::Image`]; the rest are formats imgfix
+/// sniffs for itself and can only rename, not decode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Format {
+    /// A format `image` detects, and can usually decode.
+    Image(ImageFormat),
+    /// HEIF with HEVC-coded images, as written by iPhones
+    Heic,
+    /// HEIF with some other or unknown codec
+    Heif,
+    /// JPEG XL, as a bare codestream or in its container
+    JpegXl,
+    /// Scalable Vector Graphics
+    Svg,
+    /// Photoshop document
+    Psd,
+    /// Canon RAW
+    Cr2,
+    /// Nikon RAW
+    Nef,
+    /// Sony RAW
+    Arw,
+    /// Adobe digital negative
+    Dng,
+    /// declared in the config file
+    Custom(&'static Signature),
+}
+
+/// Formats known without any config, in the order they're listed to users.
+const BUILT_IN: &[Format] = &[
+    Format::Image(ImageFormat::Png),
+    Format::Image(ImageFormat::Jpeg),
+    Format::Image(ImageFormat::Gif),
+    Format::Image(ImageFormat::WebP),
+    Format::Image(ImageFormat::Pnm),
+    Format::Image(ImageFormat::Tiff),
+    Format::Image(ImageFormat::Tga),
+    Format::Image(ImageFormat::Dds),
+    Format::Image(ImageFormat::Bmp),
+    Format::Image(ImageFormat::Ico),
+    Format::Image(ImageFormat::Hdr),
+    Format::Image(ImageFormat::OpenExr),
+    Format::Image(ImageFormat::Farbfeld),
+    Format::Image(ImageFormat::Avif),
+    Format::Heic,
+    Format::Heif,
+    Format::JpegXl,
+    Format::Svg,
+    Format::Psd,
+    Format::Cr2,
+    Format::Nef,
+    Format::Arw,
+    Format::Dng,
+];
+
+impl Format {
+    /// Looks a format up by its displayed name, ignoring case.
+    ///
+    /// Names of user-defined signatures take precedence over the built-in ones.
+    pub fn from_name(name: &str, signatures: &'static [Signature]) -> Option<Self> {
+        let custom = signatures.iter().map(Format::Custom);
+        custom
+            .chain(BUILT_IN.iter().copied())
+            .find(|format| format.to_string().eq_ignore_ascii_case(name))
+    }
+
+    /// Every extension acceptable for this format; the first is preferred.
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            Format::Image(format) => format.extensions_str(),
+            Format::Heic => &["heic", "heif", "hif"],
+            Format::Heif => &["heif", "heic", "hif"],
+            Format::JpegXl => &["jxl"],
+            Format::Svg => &["svg"],
+            Format::Psd => &["psd", "psb"],
+            Format::Cr2 => &["cr2"],
+            Format::Nef => &["nef", "nrw"],
+            Format::Arw => &["arw", "srf", "sr2"],
+            Format::Dng => &["dng"],
+            Format::Custom(signature) => signature.extensions,
+        }
+    }
+
+    /// The `image` format this corresponds to, if `image` can decode it.
+    pub fn image_format(self) -> Option<ImageFormat> {
+        match self {
+            Format::Image(format) => Some(format),
+            _ => None,
+        }
+    }
+}
+
+impl From<ImageFormat> for Format {
+    fn from(format: ImageFormat) -> Self {
+        Format::Image(format)
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Image(format) => write!(f, "{format:?}"),
+            Format::Custom(signature) => f.write_str(signature.name),
+            format => write!(f, "{format:?}"),
+        }
+    }
+}
@@ -0,0 +1,372 @@
+//! Recording renames and edits so that `imgfix undo` can reverse them.
+
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    refs::{self, Replacement, Rewrite},
+    rename, Error, Format, Preserve, Result,
+};
+
+/// One line of the journal.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Change {
+    /// A file renamed, with its sidecars.
+    Rename(Entry),
+    /// A document whose references were rewritten.
+    Edit(Edit),
+}
+
+impl Change {
+    /// The file undoing this change restores.
+    pub fn path(&self) -> &Path {
+        match self {
+            Change::Rename(entry) => &entry.from,
+            Change::Edit(edit) => &edit.edited,
+        }
+    }
+}
+
+/// Rewritten references in a document, as recorded in the journal.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Edit {
+    /// The document that was rewritten.
+    #[serde(with = "raw_path")]
+    pub edited: PathBuf,
+    /// seconds since the unix epoch at the time of the edit
+    pub timestamp: u64,
+    /// The edits that restore the document's original text.
+    pub undo: Vec<Replacement>,
+    /// The document as the edit left it.
+    #[serde(flatten)]
+    pub stamp: Stamp,
+}
+
+/// One rename, as recorded in the journal.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// The file's original name.
+    #[serde(with = "raw_path")]
+    pub from: PathBuf,
+    /// The name it was given.
+    #[serde(with = "raw_path")]
+    pub to: PathBuf,
+    /// The format its contents were detected as.
+    pub format: String,
+    /// seconds since the unix epoch at the time of the rename
+    pub timestamp: u64,
+    /// The file as the rename left it.
+    #[serde(flatten)]
+    pub stamp: Stamp,
+    /// Sidecar files renamed along with the image.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sidecars: Vec<Sidecar>,
+}
+
+/// A sidecar rename, recorded as part of its image's [`Entry`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Sidecar {
+    /// The sidecar's original name.
+    #[serde(with = "raw_path")]
+    pub from: PathBuf,
+    /// The name it was given.
+    #[serde(with = "raw_path")]
+    pub to: PathBuf,
+    /// The sidecar as the rename left it.
+    #[serde(flatten)]
+    pub stamp: Stamp,
+}
+
+/// Enough of a file's metadata to tell whether it has changed since it was renamed.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stamp {
+    /// The file's size in bytes.
+    pub len: u64,
+    /// seconds and nanoseconds since the unix epoch, where the filesystem records it
+    pub modified: Option<(u64, u32)>,
+}
+
+impl Stamp {
+    fn read(path: &Path) -> Result<Self> {
+        let meta = fs::metadata(path)?;
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|since| (since.as_secs(), since.subsec_nanos()));
+
+        Ok(Stamp {
+            len: meta.len(),
+            modified,
+        })
+    }
+}
+
+/// Appends renames to a journal file, creating it on first use.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl Journal {
+    /// A journal at `path`, which isn't opened until the first change is recorded.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Journal {
+            path: path.into(),
+            file: None,
+        }
+    }
+
+    /// Records the rename of an image from `from` to `to`, along with any of its sidecars.
+    pub fn record(
+        &mut self,
+        from: &Path,
+        to: &Path,
+        format: Format,
+        sidecars: &[(PathBuf, PathBuf)],
+    ) -> Result<()> {
+        // Relative paths would tie undo to the directory imgfix was run from.
+        let cwd = env::current_dir()?;
+        let sidecars = sidecars
+            .iter()
+            .map(|(from, to)| {
+                Ok(Sidecar {
+                    from: cwd.join(from),
+                    to: cwd.join(to),
+                    stamp: Stamp::read(to)?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        let entry = Entry {
+            from: cwd.join(from),
+            to: cwd.join(to),
+            format: format.to_string(),
+            timestamp: now(),
+            stamp: Stamp::read(to)?,
+            sidecars,
+        };
+
+        self.write(&entry)
+    }
+
+    /// Records a document whose references were rewritten, once it has been saved.
+    pub fn record_edit(&mut self, rewrite: &Rewrite) -> Result<()> {
+        let edit = Edit {
+            edited: env::current_dir()?.join(&rewrite.path),
+            timestamp: now(),
+            undo: rewrite.reversed(),
+            stamp: Stamp::read(&rewrite.path)?,
+        };
+        self.write(&edit)
+    }
+
+    fn write(&mut self, change: &impl Serialize) -> Result<()> {
+        let mut line = serde_json::to_string(change)?;
+        line.push('\n');
+        self.file()?.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    fn file(&mut self) -> Result<&mut File> {
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+}
+
+/// Reads every entry from a journal, oldest first.
+pub fn read(path: &Path) -> Result<Vec<Change>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            entries.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(entries)
+}
+
+/// Reverses a single change, refusing if anything involved has changed since.
+///
+/// `preserve` applies to documents restored from an edit, and to files moved back from another
+/// filesystem.
+pub fn undo(change: &Change, preserve: Preserve) -> Result<()> {
+    match change {
+        Change::Rename(entry) => undo_rename(entry, preserve),
+        Change::Edit(edit) => undo_edit(edit, preserve).map_err(|e| e.with_path(&edit.edited)),
+    }
+}
+
+/// Reverses a rename and those of its sidecars.
+fn undo_rename(entry: &Entry, preserve: Preserve) -> Result<()> {
+    let (from, to) = (&entry.from, &entry.to);
+    restore(from, to, &entry.stamp, preserve).map_err(|e| e.with_path(to))?;
+
+    for sidecar in &entry.sidecars {
+        let (from, to) = (&sidecar.from, &sidecar.to);
+        restore(from, to, &sidecar.stamp, preserve).map_err(|e| e.with_path(to))?;
+    }
+    Ok(())
+}
+
+fn undo_edit(edit: &Edit, preserve: Preserve) -> Result<()> {
+    let path = &edit.edited;
+    let text = fs::read_to_string(path)?;
+    if Stamp::read(path)? != edit.stamp || !refs::applies(&text, &edit.undo) {
+        return Err(Error::changed(path));
+    }
+
+    rename::write_file(path, refs::apply(&text, &edit.undo).as_bytes(), preserve)
+}
+
+fn restore(from: &Path, to: &Path, stamp: &Stamp, preserve: Preserve) -> Result<()> {
+    if rename::taken(from, to)? {
+        return Err(Error::conflict(from));
+    }
+
+    if Stamp::read(to)? != *stamp {
+        return Err(Error::changed(to));
+    }
+
+    rename::move_file(to, from, preserve)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Stores paths as plain strings where possible, falling back to raw bytes for
+/// names that aren't valid UTF-8 so they can still be restored exactly.
+mod raw_path {
+    use std::path::{Path, PathBuf};
+
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Text(String),
+        Bytes(Vec<u8>),
+    }
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        match path.to_str() {
+            Some(text) => Raw::Text(text.into()),
+            None => Raw::Bytes(to_bytes(path)),
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        match Raw::deserialize(deserializer)? {
+            Raw::Text(text) => Ok(text.into()),
+            Raw::Bytes(bytes) => from_bytes(bytes).ok_or_else(|| de::Error::custom("bad path")),
+        }
+    }
+
+    #[cfg(unix)]
+    fn to_bytes(path: &Path) -> Vec<u8> {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    }
+
+    #[cfg(not(unix))]
+    fn to_bytes(path: &Path) -> Vec<u8> {
+        path.to_string_lossy().into_owned().into_bytes()
+    }
+
+    #[cfg(unix)]
+    fn from_bytes(bytes: Vec<u8>) -> Option<PathBuf> {
+        use std::{ffi::OsString, os::unix::ffi::OsStringExt};
+        Some(OsString::from_vec(bytes).into())
+    }
+
+    #[cfg(not(unix))]
+    fn from_bytes(bytes: Vec<u8>) -> Option<PathBuf> {
+        String::from_utf8(bytes).ok().map(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use image::ImageFormat;
+
+    use super::{read, undo, Change, Journal};
+    use crate::{Error, Format, Preserve};
+
+    /// Renames `a.jpg` to `a.png` in `dir` with a sidecar, journaling it, and reads the entry
+    /// back.
+    fn renamed(dir: &Path) -> Change {
+        let (from, to) = (dir.join("a.jpg"), dir.join("a.png"));
+        let (xmp, new_xmp) = (dir.join("a.jpg.xmp"), dir.join("a.png.xmp"));
+        fs::write(&from, b"png").unwrap();
+        fs::write(&xmp, b"xmp").unwrap();
+        fs::rename(&from, &to).unwrap();
+        fs::rename(&xmp, &new_xmp).unwrap();
+
+        let path = dir.join("imgfix.journal");
+        let mut journal = Journal::new(&path);
+        let png = Format::Image(ImageFormat::Png);
+        journal.record(&from, &to, png, &[(xmp, new_xmp)]).unwrap();
+        drop(journal);
+
+        let mut changes = read(&path).unwrap();
+        assert_eq!(changes.len(), 1);
+        changes.pop().unwrap()
+    }
+
+    #[test]
+    fn renames_are_undone() {
+        let dir = tempfile::tempdir().unwrap();
+        let change = renamed(dir.path());
+        assert_eq!(change.path(), dir.path().join("a.jpg"));
+
+        undo(&change, Preserve::default()).unwrap();
+        assert_eq!(fs::read(dir.path().join("a.jpg")).unwrap(), b"png");
+        assert_eq!(fs::read(dir.path().join("a.jpg.xmp")).unwrap(), b"xmp");
+        assert!(!dir.path().join("a.png").exists());
+        assert!(!dir.path().join("a.png.xmp").exists());
+    }
+
+    #[test]
+    fn undo_refuses_changed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let change = renamed(dir.path());
+        fs::write(dir.path().join("a.png"), b"edited since").unwrap();
+
+        let undone = undo(&change, Preserve::default());
+        assert!(matches!(undone, Err(Error::Changed(_))));
+        assert!(!dir.path().join("a.jpg").exists());
+    }
+
+    #[test]
+    fn undo_refuses_to_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let change = renamed(dir.path());
+        fs::write(dir.path().join("a.jpg"), b"someone else").unwrap();
+
+        let undone = undo(&change, Preserve::default());
+        assert!(matches!(undone, Err(Error::Conflict(_))));
+        assert_eq!(fs::read(dir.path().join("a.jpg")).unwrap(), b"someone else");
+        assert_eq!(fs::read(dir.path().join("a.png")).unwrap(), b"png");
+    }
+}
@@ -0,0 +1,50 @@
+//! Find images whose extensions don't match their contents, and fix them.
+//!
+//! [`Checker`] inspects a file (or any reader) and returns a [`Verdict`]; [`Fixer`] renames a
+//! mismatched file and its sidecars to the extension its contents call for, in place or into an
+//! [`OutputDir`], while [`Converter`] instead re-encodes it into the format its extension claims.
+//!
+//! ```no_run
+//! use imgfix::{Checker, Fixer, Verdict};
+//!
+//! let path = std::path::Path::new("photo.png");
+//! if let Verdict::Mismatch { detected, proposed } = Checker::new().check_path(path) {
+//!     Fixer::default().fix(path, path, detected, proposed)?;
+//! }
+//! # Ok::<(), imgfix::Error>(())
+//! ```
+
+#![warn(missing_docs)]
+
+mod check;
+mod config;
+mod convert;
+mod detect;
+mod error;
+mod fix;
+mod format;
+mod metadata;
+mod prefer;
+mod sidecar;
+mod sort;
+
+pub mod archive;
+pub mod journal;
+pub mod refs;
+pub mod rename;
+pub mod walk;
+
+pub use check::{Checker, Verdict};
+pub use config::{Config, Signature};
+pub use convert::{Converted, Converter, PngCompression};
+pub use detect::{
+    detect_format, guess_format, is_allowed_extension, preferred_extension, read_extension,
+    read_header, HEADER_LEN,
+};
+pub use error::{BadImage, BadImageKind, Error, Result};
+pub use fix::{Fixed, Fixer, OutputDir};
+pub use format::Format;
+pub use metadata::Preserve;
+pub use prefer::Preferences;
+pub use sidecar::{is_sidecar, DEFAULT_EXTENSIONS as DEFAULT_SIDECARS};
+pub use sort::Template;
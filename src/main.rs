@@ -1,130 +1,1124 @@
 use std::{
-    error, fmt, fs, io,
-    path::{self, Path},
+    ffi::OsStr,
+    fs, io,
+    path::{self, Path, PathBuf},
     process,
+    time::Duration,
 };
 
-use clap::Parser;
-use image::ImageFormat;
-use uncased::UncasedStr;
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+use imgfix::{
+    archive::{self, ArchiveKind},
+    journal::{self, Journal},
+    refs::{Rewrite, Rewriter},
+    rename::{Conflict, Renamed},
+    walk::{self, Found, Walker},
+    BadImage, Checker, Config, Converter, Error, Fixer, Format, OutputDir, PngCompression,
+    Preserve, Result, Template, Verdict,
+};
+use output::{Action, Record, Report};
+
+mod output;
+mod pipeline;
+mod watch;
+
+#[derive(Clone, Debug, Parser)]
+#[command(subcommand_negates_reqs = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// config file declaring extra signatures [default: $IMGFIX_CONFIG or ~/.config/imgfix/config.toml]
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// images to be corrected; `-` reads paths from stdin
+    #[arg(required_unless_present = "stdin")]
+    images: Vec<PathBuf>,
+
+    /// read paths from stdin, one per line
+    #[arg(long)]
+    stdin: bool,
+
+    /// paths read from stdin are separated by NUL rather than newline, as with `find -print0`
+    #[arg(short = '0', long)]
+    null: bool,
+
+    /// correct image names
+    #[arg(short, long)]
+    force: bool,
+
+    /// what to do when the corrected name is already taken
+    #[arg(long, value_enum, default_value_t)]
+    on_conflict: Conflict,
+
+    /// move corrected images into this directory, keeping their place under the directory they
+    /// were found in
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["convert", "update_refs"])]
+    output_dir: Option<PathBuf>,
+
+    /// with --output-dir, copy corrected images rather than moving them
+    #[arg(long, requires = "output_dir")]
+    copy: bool,
+
+    /// report every failure at the end instead of stopping (default)
+    #[arg(long, overrides_with = "fail_fast")]
+    keep_going: bool,
+
+    /// stop at the first file that can't be checked or fixed
+    #[arg(long, overrides_with = "keep_going")]
+    fail_fast: bool,
+
+    /// number of files to examine concurrently
+    #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    jobs: u16,
+
+    /// report results as they finish rather than in input order
+    #[arg(long)]
+    unordered: bool,
+
+    /// journal file recording each rename made with --force
+    #[arg(long, default_value = "imgfix.journal")]
+    journal: PathBuf,
+
+    /// don't record renames
+    #[arg(long, conflicts_with = "journal")]
+    no_journal: bool,
+
+    /// extensions of sidecar files renamed along with their image, as IMG_1.xmp or IMG_1.png.xmp
+    #[arg(long, value_delimiter = ',', default_values = imgfix::DEFAULT_SIDECARS)]
+    sidecars: Vec<String>,
+
+    /// leave sidecar files alone
+    #[arg(long, conflicts_with = "sidecars")]
+    no_sidecars: bool,
+
+    /// detect and assign an extension to files that have none
+    #[arg(long)]
+    add_missing: bool,
+
+    /// preferred extension per format, e.g. jpeg=jpeg,tiff=tif
+    #[arg(long, value_delimiter = ',', value_parser = parse_preference)]
+    prefer: Vec<(String, String)>,
+
+    /// also rename files whose extension is allowed but not the preferred, lowercase one
+    #[arg(long)]
+    canonical: bool,
+
+    /// rewrite references to renamed images in the HTML, Markdown and CSS files matching this glob
+    #[arg(long, value_name = "GLOB", value_parser = parse_glob)]
+    update_refs: Vec<String>,
+
+    /// directory that references starting with `/` are relative to
+    #[arg(
+        long,
+        value_name = "DIR",
+        default_value = ".",
+        requires = "update_refs"
+    )]
+    site_root: PathBuf,
 
-type Result<T, E = Error> = std::result::Result<T, E>;
+    /// re-encode mismatched files into the format their extension names instead of renaming them
+    #[arg(long)]
+    convert: bool,
 
-#[derive(Debug, thiserror::Error)]
-enum Error {
-    #[error(transparent)]
-    Image(BadImage),
+    /// JPEG quality for --convert, from 1 to 100
+    #[arg(long, default_value_t = 90, value_parser = clap::value_parser!(u8).range(1..=100), requires = "convert")]
+    jpeg_quality: u8,
 
-    #[error(transparent)]
-    Io(#[from] io::Error),
+    /// PNG compression for --convert
+    #[arg(long, value_enum, default_value_t, requires = "convert")]
+    png_compression: PngCompression,
 
-    #[error("no usable extension: {0}")]
-    BadExtension(String),
+    /// with --convert, copy each original to <name>.bak before replacing it
+    #[arg(long, requires = "convert")]
+    backup: bool,
+
+    /// fully decode each image, reporting truncated and corrupt files
+    #[arg(long)]
+    verify: bool,
+
+    /// with --force, move images that fail --verify into this directory
+    #[arg(long, value_name = "DIR", requires = "verify")]
+    quarantine: Option<PathBuf>,
+
+    /// metadata to keep when a file is rewritten, copied or moved to another filesystem: any of
+    /// timestamps, mode, xattr and owner, or all or none
+    #[arg(long, value_name = "LIST", default_value_t)]
+    preserve: Preserve,
+
+    /// how to report results
+    #[arg(long, value_enum, default_value_t)]
+    format: output::Format,
+
+    /// descend into directories
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// maximum depth to descend below each directory
+    #[arg(long, requires = "recursive")]
+    max_depth: Option<usize>,
+
+    /// follow symbolic links while descending
+    #[arg(short = 'L', long, requires = "recursive")]
+    follow_symlinks: bool,
+
+    /// include hidden files and directories while descending, or while watching
+    #[arg(long)]
+    include_hidden: bool,
 }
 
-impl Error {
-    fn bad_extension(path: impl Into<String>) -> Self {
-        Error::BadExtension(path.into())
+#[derive(Clone, Debug, Subcommand)]
+enum Command {
+    /// reverse the renames recorded in a journal, newest first
+    Undo {
+        /// journal written by a previous run with --force
+        journal: PathBuf,
+    },
+
+    /// fix images as they land in a directory, until interrupted
+    ///
+    /// Options for checking and fixing go before `watch`, e.g. `imgfix --force watch drop/`.
+    Watch {
+        /// directory to watch; add -r to include its subdirectories
+        dir: PathBuf,
+
+        /// seconds a file must go unchanged before it's checked
+        #[arg(long, default_value = "2", value_parser = parse_seconds)]
+        settle: Duration,
+    },
+
+    /// move images into directories by their detected format, walking <SRC> recursively
+    ///
+    /// Options for checking and moving go before `sort`, e.g. `imgfix --force sort dump/ --into
+    /// sorted/`.
+    Sort {
+        /// file or directory to sort
+        src: PathBuf,
+
+        /// directory to sort into
+        #[arg(long, value_name = "DIR")]
+        into: PathBuf,
+
+        /// path of each file under --into, from {format}, {year}, {month}, {day} (of the
+        /// modification time, in UTC), {name} and {ext}
+        #[arg(long, default_value_t)]
+        template: Template,
+    },
+
+    /// list the signatures declared in the config file
+    Signatures {
+        /// only validate the config file
+        #[arg(long)]
+        check: bool,
+    },
+}
+
+impl Args {
+    fn paths(&self) -> impl Iterator<Item = Result<PathBuf>> + '_ {
+        let stdin = self.stdin.then(|| self.stdin_paths()).into_iter().flatten();
+        self.images
+            .iter()
+            .flat_map(|path| match path.as_os_str() == "-" {
+                true => self.stdin_paths(),
+                false => Box::new(Some(Ok(path.clone())).into_iter()),
+            })
+            .chain(stdin)
+    }
+
+    /// Paths read lazily from stdin, so that huge lists never sit in memory.
+    fn stdin_paths(&self) -> Box<dyn Iterator<Item = Result<PathBuf>>> {
+        let separator = if self.null { b'\0' } else { b'\n' };
+        Box::new(walk::read_paths(io::stdin().lock(), separator))
+    }
+
+    fn walker(&self) -> Walker {
+        Walker {
+            recursive: self.recursive,
+            max_depth: self.max_depth,
+            follow_symlinks: self.follow_symlinks,
+            include_hidden: self.include_hidden,
+        }
+    }
+
+    fn config(&self) -> Result<Option<(PathBuf, Config)>> {
+        let path = match &self.config {
+            Some(path) => path.clone(),
+            None => match Config::default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(None),
+            },
+        };
+
+        let config = Config::load(&path)?;
+        Ok(Some((path, config)))
+    }
+
+    fn checker(&self, config: Config) -> Result<Checker> {
+        let mut preferences = config.preferences;
+        for (name, extension) in &self.prefer {
+            let format = Format::from_name(name, config.signatures)
+                .ok_or_else(|| Error::Preference(format!("no such format: {name}")))?;
+            preferences
+                .set(format, extension)
+                .map_err(Error::Preference)?;
+        }
+
+        Ok(Checker {
+            allow_missing: self.add_missing,
+            signatures: config.signatures,
+            preferences,
+            canonical: self.canonical,
+            verify: self.verify,
+        })
     }
 
-    fn bad_image(path: impl Into<String>, error: image::ImageError) -> Self {
-        Error::Image(BadImage {
-            path: path.into(),
-            error,
+    fn converter(&self) -> Option<Converter> {
+        self.convert.then_some(Converter {
+            jpeg_quality: self.jpeg_quality,
+            png_compression: self.png_compression,
+            backup: self.backup,
+            preserve: self.preserve,
         })
     }
+
+    fn fixer(&self) -> Fixer {
+        Fixer {
+            conflict: self.on_conflict,
+            journal: (!self.no_journal).then(|| Journal::new(&self.journal)),
+            sidecars: self.sidecars().to_vec(),
+            output: self.output_dir.clone().map(|dir| OutputDir {
+                dir,
+                copy: self.copy,
+            }),
+            preserve: self.preserve,
+        }
+    }
+
+    /// Whether `path` is a sidecar, which moves with its image, or the journal this run
+    /// appends to; neither is checked on its own.
+    fn is_passenger(&self, path: &Path) -> bool {
+        imgfix::is_sidecar(path, self.sidecars())
+            || !self.no_journal && same_file::is_same_file(path, &self.journal).unwrap_or(false)
+    }
+
+    fn sidecars(&self) -> &[String] {
+        match self.no_sidecars {
+            true => &[],
+            false => &self.sidecars,
+        }
+    }
+}
+
+fn parse_preference(pair: &str) -> Result<(String, String), String> {
+    let (format, extension) = pair
+        .split_once('=')
+        .ok_or_else(|| format!("expected FORMAT=EXTENSION, got {pair}"))?;
+    Ok((format.trim().into(), extension.trim().into()))
+}
+
+fn parse_glob(pattern: &str) -> Result<String, String> {
+    glob::Pattern::new(pattern)
+        .map(|_| pattern.into())
+        .map_err(|e| e.to_string())
 }
 
-#[derive(Debug)]
-struct BadImage {
-    path: String,
-    error: image::ImageError,
+fn parse_seconds(seconds: &str) -> Result<Duration, String> {
+    seconds
+        .parse()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("expected a number of seconds, got {seconds}"))
 }
 
-impl fmt::Display for BadImage {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} ({})", self.error, self.path)
+fn main() {
+    let args = Args::parse();
+    // A JSON array is only written once the run ends, which a watch never does on its own.
+    if matches!(args.command, Some(Command::Watch { .. })) && args.format == output::Format::Json {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--format json can't be used with watch; use --format ndjson",
+            )
+            .exit();
+    }
+    // Watches see hidden files land even without descending; runs only skip them while walking.
+    let watching = matches!(args.command, Some(Command::Watch { .. }));
+    if args.include_hidden && !args.recursive && !watching {
+        Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "--include-hidden requires --recursive, except with watch",
+            )
+            .exit();
     }
+
+    let result = match &args.command {
+        Some(Command::Undo { journal }) => undo(journal, args.preserve),
+        Some(Command::Signatures { check }) => signatures(&args, *check),
+        Some(Command::Watch { dir, settle }) => watch(&args, dir, *settle),
+        Some(Command::Sort {
+            src,
+            into,
+            template,
+        }) => sort(&args, src, into, template),
+        None => run(&args),
+    };
+
+    let code = match result {
+        Ok(summary) => summary.finish(),
+        Err(e) => {
+            eprintln!("{e}");
+            EXIT_ERRORS
+        }
+    };
+    process::exit(code);
 }
 
-impl error::Error for BadImage {}
+/// Exit status when every file was already correctly named.
+const EXIT_CLEAN: i32 = 0;
 
-#[derive(Clone, Debug, Parser)]
-struct Args {
-    /// images to be corrected
-    #[arg(required = true)]
-    images: Vec<String>,
+/// Exit status when at least one file's extension didn't match its contents.
+const EXIT_MISMATCHES: i32 = 1;
 
-    /// correct image names
-    #[arg(short, long)]
-    force: bool,
+/// Exit status when at least one file couldn't be checked or fixed.
+const EXIT_ERRORS: i32 = 2;
+
+#[derive(Debug, Default)]
+struct Summary {
+    checked: usize,
+    mismatches: usize,
+    missing: usize,
+    errors: Vec<Error>,
 }
 
-impl Args {
-    fn paths(&self) -> impl Iterator<Item = &str> {
-        self.images.iter().map(AsRef::as_ref)
+impl Summary {
+    fn add(&mut self, outcome: Outcome) {
+        self.checked += 1;
+        self.count(outcome);
+    }
+
+    /// Counts a misnamed file without counting it as checked again.
+    fn count(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Clean => {}
+            Outcome::Mismatch => self.mismatches += 1,
+            Outcome::Missing => self.missing += 1,
+        }
+    }
+
+    fn fail(&mut self, error: Error) {
+        self.checked += 1;
+        self.errors.push(error);
+    }
+
+    /// Prints any collected errors and returns the exit status.
+    fn finish(self) -> i32 {
+        if self.errors.is_empty() {
+            return if self.mismatches == 0 && self.missing == 0 {
+                EXIT_CLEAN
+            } else {
+                EXIT_MISMATCHES
+            };
+        }
+
+        eprintln!(
+            "{} checked, {} mismatched, {} missing an extension, {} failed:",
+            self.checked,
+            self.mismatches,
+            self.missing,
+            self.errors.len()
+        );
+        for e in &self.errors {
+            eprintln!("  {e}");
+        }
+        EXIT_ERRORS
     }
 }
 
-fn main() {
-    if let Err(e) = run(&Args::parse()) {
-        eprintln!("{e}");
-        process::exit(1);
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    Clean,
+    Mismatch,
+    /// The file had no extension and one was proposed or assigned.
+    Missing,
+}
+
+fn run(args: &Args) -> Result<Summary> {
+    let walker = args.walker();
+    let config = args.config()?.map(|(_, config)| config).unwrap_or_default();
+    let checker = args.checker(config)?;
+    let mut session = Session::new(args);
+
+    // Checks run in parallel; renames stay on this thread so two files can't race for the
+    // same name.
+    let check = |found: Result<Found>| match found {
+        Ok(found) => {
+            let checked = check(&checker, &found.path);
+            (Some(found), checked)
+        }
+        Err(e) => (None, Err(e)),
+    };
+
+    let files = walker.files(args.paths()).filter(|found| match found {
+        Ok(found) => !args.is_passenger(&found.path),
+        // A directory named without --recursive is worth a warning, not a failure.
+        Err(e @ Error::SkippedDirectory(_)) => {
+            eprintln!("warning: {e}");
+            false
+        }
+        Err(_) => true,
+    });
+    let result = pipeline::for_each(
+        files,
+        args.jobs.into(),
+        !args.unordered,
+        check,
+        |(found, checked)| session.handle_checked(found, checked).map(drop),
+    );
+
+    if result.is_ok() && !args.update_refs.is_empty() {
+        session.update_refs()?;
     }
+
+    let summary = session.finish()?;
+    result.map(|()| summary)
 }
 
-fn run(args: &Args) -> Result<()> {
-    for path in args.paths() {
-        let extension = read_extension(path)?;
+fn watch(args: &Args, dir: &Path, settle: Duration) -> Result<Summary> {
+    let config = args.config()?.map(|(_, config)| config).unwrap_or_default();
+    let checker = args.checker(config)?;
+    let mut session = Session::new(args);
+    session.log_errors = true;
 
-        // Not being able to figure out one file type isn't the end of the world.
-        let format = match guess_format(path) {
-            Ok(format) => format,
-            Err(e) => {
-                eprintln!("warning: {e}");
-                continue;
+    let options = watch::Options {
+        recursive: args.recursive,
+        include_hidden: args.include_hidden,
+        settle,
+    };
+    eprintln!("watching {}", dir.display());
+    let result = watch::watch(dir, options, |path| {
+        // Appending to the journal would otherwise look like a new file landing, and sidecars
+        // are renamed with their image.
+        if args.is_passenger(&path) {
+            return Ok(Vec::new());
+        }
+        let checked = check(&checker, &path);
+        let found = Found {
+            relative: path.strip_prefix(dir).unwrap_or(&path).into(),
+            path,
+        };
+        session.handle_checked(Some(found), checked)
+    });
+
+    let summary = session.finish()?;
+    result.map(|()| summary)
+}
+
+fn sort(args: &Args, src: &Path, into: &Path, template: &Template) -> Result<Summary> {
+    let config = args.config()?.map(|(_, config)| config).unwrap_or_default();
+    let checker = args.checker(config)?;
+    let walker = Walker {
+        recursive: true,
+        ..args.walker()
+    };
+    let mut session = Session::new(args);
+
+    // Sorting into a directory under `src` mustn't walk into the files already sorted.
+    if args.force {
+        fs::create_dir_all(into).map_err(|source| Error::Access {
+            path: into.into(),
+            source,
+        })?;
+    }
+    let sorted = fs::canonicalize(into).ok();
+    let is_sorted = |path: &Path| {
+        sorted
+            .as_ref()
+            .is_some_and(|sorted| fs::canonicalize(path).is_ok_and(|path| path.starts_with(sorted)))
+    };
+
+    let check = |found: Result<Found>| match found {
+        Ok(found) => {
+            let verdict = checker.check_path(&found.path).into_result(&found.path);
+            (Some(found.path), verdict)
+        }
+        Err(e) => (None, Err(e)),
+    };
+
+    let paths = Some(Ok(src.to_owned())).into_iter();
+    let files = walker.files(paths).filter(|found| match found {
+        Ok(found) => !is_sorted(&found.path) && !args.is_passenger(&found.path),
+        Err(_) => true,
+    });
+    let result = pipeline::for_each(
+        files,
+        args.jobs.into(),
+        !args.unordered,
+        check,
+        |(path, verdict)| session.sort(path, verdict, into, template),
+    );
+
+    let summary = session.finish()?;
+    result.map(|()| summary)
+}
+
+/// What checking one input found.
+enum Checked {
+    File(Verdict),
+    Archive(ArchiveKind, Vec<archive::Entry>),
+}
+
+/// Checks a file, or each file inside it if it's an archive.
+fn check(checker: &Checker, path: &Path) -> Result<Checked> {
+    match ArchiveKind::from_path(path) {
+        Some(kind) => {
+            archive::check(checker, path, kind).map(|entries| Checked::Archive(kind, entries))
+        }
+        None => checker
+            .check_path(path)
+            .into_result(path)
+            .map(Checked::File),
+    }
+}
+
+/// The state shared by every file checked in one run.
+struct Session<'a> {
+    args: &'a Args,
+    fixer: Fixer,
+    converter: Option<Converter>,
+    report: Report,
+    summary: Summary,
+    /// Print each failure as it happens rather than only at the end.
+    log_errors: bool,
+    /// Each rename made or proposed, for --update-refs.
+    renames: Vec<(PathBuf, PathBuf)>,
+}
+
+impl<'a> Session<'a> {
+    fn new(args: &'a Args) -> Self {
+        Session {
+            args,
+            fixer: args.fixer(),
+            converter: args.converter(),
+            report: Report::new(args.format),
+            summary: Summary::default(),
+            log_errors: false,
+            renames: Vec::new(),
+        }
+    }
+
+    /// Acts on one checked input, returning the paths it wrote.
+    fn handle_checked(
+        &mut self,
+        found: Option<Found>,
+        checked: Result<Checked>,
+    ) -> Result<Vec<PathBuf>> {
+        match (checked, found) {
+            (Ok(Checked::Archive(kind, entries)), Some(found)) => {
+                self.handle_archive(&found.path, kind, entries)?;
+                Ok(vec![found.path])
             }
+            (Ok(Checked::File(verdict)), found) => self.handle(found, Ok(verdict)),
+            (Err(e), found) => self.handle(found, Err(e)),
+            (Ok(_), None) => unreachable!("only paths are checked"),
+        }
+    }
+
+    /// Reports on each image in an archive, renaming entries in one rewrite of the archive.
+    ///
+    /// Archives often hold other files too, so entries of unknown formats are passed over. They
+    /// are only ever fixed in place, so with --output-dir the renames are just proposed.
+    fn handle_archive(
+        &mut self,
+        path: &Path,
+        kind: ArchiveKind,
+        entries: Vec<archive::Entry>,
+    ) -> Result<()> {
+        let args = self.args;
+        // Nothing is reported as renamed until the archive has actually been rewritten.
+        let plan =
+            (args.force && args.output_dir.is_none()).then(|| archive::Plan::new(path, &entries));
+        let rewritten = match &plan {
+            Some(plan) if !plan.is_empty() => plan.apply(path, kind, args.preserve),
+            _ => Ok(()),
         };
+        let mut outcomes = plan.map(|plan| plan.outcomes);
 
-        if !is_allowed_extension(extension, format) {
-            let from = Path::new(path);
+        for (idx, entry) in entries.into_iter().enumerate() {
+            let shown = archive::entry_path(path, &entry.name);
+            let (detected, proposed) = match entry.verdict {
+                Verdict::Ok { format } => {
+                    self.summary.add(Outcome::Clean);
+                    self.report
+                        .record(Record::new(&shown, format).entry(&entry.name))?;
+                    continue;
+                }
+                Verdict::Mismatch { detected, proposed } => (detected, proposed),
+                Verdict::Unknown => continue,
+                Verdict::Error(e) => {
+                    self.fail(error_record(Some(&shown), &e).entry(&entry.name), e)?;
+                    continue;
+                }
+            };
 
-            if args.force {
-                let to = from.with_extension(preferred_extension(format));
-                fs::rename(from, &to)?;
-                println!("{}", display_filename(&to));
-            } else {
-                let preferred_extension = preferred_extension(format);
-                println!("{} -> {preferred_extension}", display_filename(from));
+            let mut record = Record::new(&shown, detected).entry(&entry.name);
+            record.proposed = Some(proposed);
+            let planned = outcomes.as_mut().and_then(|outcomes| outcomes[idx].take());
+            record.action = match (&planned, &rewritten) {
+                (None, _) => Action::WouldRename,
+                (Some(renamed @ Renamed::Skipped { .. }), _) => {
+                    warn_rename(&shown, renamed);
+                    Action::Skipped
+                }
+                (Some(renamed), Ok(())) => {
+                    record.target = renamed.target().map(Into::into);
+                    Action::Renamed
+                }
+                (Some(_), Err(e)) => {
+                    record.error = Some(e.to_string());
+                    Action::Error
+                }
+            };
+            self.summary.add(outcome(&record));
+            self.report.record(record)?;
+        }
+
+        match rewritten {
+            Ok(()) => Ok(()),
+            Err(e) => self.fail(Record::error(Some(path), &e), e),
+        }
+    }
+
+    /// Acts on one checked file, returning the paths it wrote.
+    fn handle(&mut self, found: Option<Found>, verdict: Result<Verdict>) -> Result<Vec<PathBuf>> {
+        let args = self.args;
+        let result = verdict.and_then(|verdict| {
+            let found = found.as_ref().unwrap();
+            match &self.converter {
+                Some(converter) => convert(args, found, verdict, converter, &mut self.fixer),
+                None => fix(args, found, verdict, &mut self.fixer),
+            }
+        });
+        let path = found.map(|found| found.path);
+
+        match result {
+            Ok(record) => {
+                let written = match record.action {
+                    Action::Converted => path.iter().chain(&record.target).cloned().collect(),
+                    _ => record.target.iter().cloned().collect(),
+                };
+                let renamed = match (record.action, &path, record.proposed) {
+                    (Action::Renamed, Some(path), _) => record.target.clone().map(|to| (path, to)),
+                    (Action::WouldRename, Some(path), Some(proposed)) => {
+                        Some((path, path.with_extension(proposed)))
+                    }
+                    _ => None,
+                };
+                if let (false, Some((from, to))) = (args.update_refs.is_empty(), renamed) {
+                    self.renames.push((from.clone(), to));
+                }
+
+                self.summary.add(outcome(&record));
+                self.report.record(record)?;
+                Ok(written)
+            }
+            Err(e) => {
+                let mut record = error_record(path.as_deref(), &e);
+                if let (Error::Image(bad), Some(dir)) = (&e, &args.quarantine) {
+                    if bad.is_damaged() {
+                        quarantine(args, bad, dir, &mut self.fixer, &mut record);
+                    }
+                }
+                let written = record.target.iter().cloned().collect();
+                self.fail(record, e)?;
+                Ok(written)
+            }
+        }
+    }
+
+    /// Moves one checked file to where `template` says, or shows where it would go without
+    /// --force.
+    fn sort(
+        &mut self,
+        path: Option<PathBuf>,
+        verdict: Result<Verdict>,
+        into: &Path,
+        template: &Template,
+    ) -> Result<()> {
+        let args = self.args;
+        let result = verdict.and_then(|verdict| {
+            let path = path.as_deref().unwrap();
+            let (format, proposed, outcome) = match verdict {
+                Verdict::Ok { format } => (format, None, Outcome::Clean),
+                Verdict::Mismatch { detected, proposed } => match path.extension() {
+                    Some(_) => (detected, Some(proposed), Outcome::Mismatch),
+                    None => (detected, Some(proposed), Outcome::Missing),
+                },
+                Verdict::Unknown | Verdict::Error(_) => unreachable!("filtered by into_result"),
+            };
+
+            // Files are given the extension their contents call for on the way.
+            let extension = match proposed {
+                Some(proposed) => OsStr::new(proposed),
+                None => path.extension().unwrap_or_default(),
+            };
+            let mut record = Record::new(path, format);
+            record.proposed = proposed;
+            let to = template.target(into, path, format, extension)?;
+            if !args.force {
+                record.action = Action::WouldRename;
+                record.target = Some(to);
+                return Ok((record, outcome));
             }
+
+            let fixed = self.fixer.sort(path, to, format)?;
+            warn_rename(path, &fixed.renamed);
+            for (sidecar, moved) in &fixed.sidecars {
+                match moved {
+                    Ok(renamed) => warn_rename(sidecar, renamed),
+                    Err(e) => eprintln!("warning: sidecar not moved: {e}"),
+                }
+            }
+            match fixed.renamed.target() {
+                Some(to) => {
+                    record.action = Action::Renamed;
+                    record.target = Some(to.into());
+                }
+                None => record.action = Action::Skipped,
+            }
+            Ok((record, outcome))
+        });
+
+        match result {
+            Ok((record, outcome)) => {
+                self.summary.add(outcome);
+                Ok(self.report.record(record)?)
+            }
+            Err(e) => self.fail(Record::error(path.as_deref(), &e), e),
+        }
+    }
+
+    /// Reports a failure, which only ends the run with --fail-fast.
+    fn fail(&mut self, record: Record, e: Error) -> Result<()> {
+        // A damaged image can be misnamed too.
+        if record.proposed.is_some() {
+            self.summary.count(outcome(&record));
+        }
+        self.report.record(record)?;
+        if self.args.fail_fast {
+            return Err(e);
+        }
+        if self.log_errors {
+            eprintln!("{e}");
+        }
+        self.summary.fail(e);
+        Ok(())
+    }
+
+    /// Rewrites references to the renamed images, or shows the changes as a diff without
+    /// --force.
+    fn update_refs(&mut self) -> Result<()> {
+        let args = self.args;
+        let mut rewriter = Rewriter::new(&args.site_root)?;
+        for (from, to) in &self.renames {
+            rewriter.add(from, to)?;
+        }
+        if rewriter.is_empty() {
+            return Ok(());
+        }
+
+        let documents = args
+            .update_refs
+            .iter()
+            .flat_map(|pattern| glob::glob(pattern).expect("validated by clap"));
+        for document in documents {
+            let result = document
+                .map_err(|e| Error::from(io::Error::from(e)))
+                .and_then(|document| match fs::metadata(&document)?.is_file() {
+                    true => rewriter.rewrite_file(&document),
+                    false => Ok(None),
+                })
+                .and_then(|rewrite| match rewrite {
+                    Some(rewrite) => self.apply_rewrite(&rewrite),
+                    None => Ok(()),
+                });
+
+            if let Err(e) = result {
+                if args.fail_fast {
+                    return Err(e);
+                }
+                self.summary.fail(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_rewrite(&mut self, rewrite: &Rewrite) -> Result<()> {
+        if !self.args.force {
+            // Diffs would corrupt machine-readable output, so they go to stderr there.
+            return match self.args.format {
+                output::Format::Text => output::write_diff(&mut io::stdout().lock(), rewrite),
+                _ => output::write_diff(&mut io::stderr().lock(), rewrite),
+            }
+            .map_err(Into::into);
+        }
+
+        rewrite.save(self.args.preserve)?;
+        if let Some(journal) = &mut self.fixer.journal {
+            journal.record_edit(rewrite)?;
+        }
+        if self.args.format == output::Format::Text {
+            println!("{}", rewrite.path.display());
         }
+        Ok(())
     }
 
-    Ok(())
+    fn finish(self) -> Result<Summary> {
+        self.report.finish()?;
+        Ok(self.summary)
+    }
 }
 
-fn display_filename(path: &Path) -> path::Display {
-    Path::new(path.file_name().unwrap_or(path.as_os_str())).display()
+fn fix(args: &Args, found: &Found, verdict: Verdict, fixer: &mut Fixer) -> Result<Record> {
+    let path = &*found.path;
+    let (detected, proposed) = match verdict {
+        Verdict::Mismatch { detected, proposed } => (detected, proposed),
+        Verdict::Ok { format } => return Ok(Record::new(path, format)),
+        Verdict::Unknown | Verdict::Error(_) => unreachable!("filtered by into_result"),
+    };
+
+    let mut record = Record::new(path, detected);
+    record.proposed = Some(proposed);
+    if !args.force {
+        record.action = Action::WouldRename;
+        if fixer.output.is_some() {
+            record.target = Some(fixer.target(path, &found.relative, proposed));
+        }
+        return Ok(record);
+    }
+
+    let fixed = fixer.fix(path, &found.relative, detected, proposed)?;
+    warn_rename(path, &fixed.renamed);
+    for (sidecar, moved) in &fixed.sidecars {
+        match moved {
+            Ok(renamed) => warn_rename(sidecar, renamed),
+            Err(e) => eprintln!("warning: sidecar not renamed: {e}"),
+        }
+    }
+
+    match fixed.renamed.target() {
+        Some(to) => {
+            record.action = match args.copy {
+                true => Action::Copied,
+                false => Action::Renamed,
+            };
+            record.target = Some(to.into());
+        }
+        None => record.action = Action::Skipped,
+    }
+
+    Ok(record)
 }
 
-fn preferred_extension(format: ImageFormat) -> &'static str {
-    format.extensions_str().first().unwrap()
+/// Converts a mismatched file, falling back to renaming when its extension names the format it
+/// already has (as with --canonical) or one that can't be written.
+fn convert(
+    args: &Args,
+    found: &Found,
+    verdict: Verdict,
+    converter: &Converter,
+    fixer: &mut Fixer,
+) -> Result<Record> {
+    let path = &*found.path;
+    let detected = match verdict {
+        Verdict::Mismatch { detected, .. }
+            if Converter::target(path, detected)
+                .is_some_and(|to| Some(to) != detected.image_format()) =>
+        {
+            detected
+        }
+        verdict => return fix(args, found, verdict, fixer),
+    };
+
+    let mut record = Record::new(path, detected);
+    if !args.force {
+        record.action = Action::WouldConvert;
+        return Ok(record);
+    }
+
+    let converted = converter.convert(path, detected)?;
+    record.action = Action::Converted;
+    record.target = converted.backup;
+    Ok(record)
+}
+
+/// Moves a damaged image aside; it still counts as a failure either way.
+fn quarantine(args: &Args, bad: &BadImage, dir: &Path, fixer: &mut Fixer, record: &mut Record) {
+    record.format = bad.format;
+    if !args.force {
+        record.action = Action::WouldQuarantine;
+        record.target = bad.path.file_name().map(|name| dir.join(name));
+        return;
+    }
+
+    let detected = bad.format.expect("damaged images have a format");
+    match fixer.quarantine(&bad.path, dir, detected) {
+        Ok(moved) => {
+            record.action = Action::Quarantined;
+            record.target = moved.target().map(Into::into);
+        }
+        Err(e) => eprintln!("warning: could not quarantine: {e}"),
+    }
 }
 
-fn is_allowed_extension(extension: &str, format: ImageFormat) -> bool {
-    let extension: &UncasedStr = extension.into();
-    format.extensions_str().iter().any(|&ext| ext == extension)
+/// The record for a file that couldn't be checked or fixed, keeping the extension a damaged
+/// image should have had.
+fn error_record(path: Option<&Path>, e: &Error) -> Record {
+    let mut record = Record::error(path, e);
+    if let Error::Image(bad) = e {
+        if bad.proposed.is_some() {
+            record.format = bad.format;
+            record.proposed = bad.proposed;
+        }
+    }
+    record
 }
 
-fn guess_format(path: &str) -> Result<ImageFormat> {
-    let buffer = fs::read(path)?;
-    let format = image::guess_format(&buffer).map_err(|e| Error::bad_image(path, e))?;
-    Ok(format)
+fn outcome(record: &Record) -> Outcome {
+    match record.action {
+        Action::None => Outcome::Clean,
+        _ if record.extension.is_none() => Outcome::Missing,
+        _ => Outcome::Mismatch,
+    }
 }
 
-fn read_extension(path: &str) -> Result<&str> {
-    let (_stem, extension) = path
-        .rsplit_once('.')
-        .ok_or_else(|| Error::bad_extension(path))?;
-    Ok(extension)
+fn undo(path: &Path, preserve: Preserve) -> Result<Summary> {
+    let mut summary = Summary::default();
+    for entry in journal::read(path)?.iter().rev() {
+        // One file having changed shouldn't stop the rest from being restored.
+        match journal::undo(entry, preserve) {
+            Ok(()) => {
+                println!("{}", display_filename(entry.path()));
+                summary.add(Outcome::Clean);
+            }
+            Err(e) => summary.fail(e),
+        }
+    }
+    Ok(summary)
+}
+
+fn signatures(args: &Args, check: bool) -> Result<Summary> {
+    let Some((path, config)) = args.config()? else {
+        eprintln!("no config file");
+        return Ok(Summary::default());
+    };
+
+    if check {
+        println!(
+            "{}: {} signatures ok",
+            path.display(),
+            config.signatures.len()
+        );
+        return Ok(Summary::default());
+    }
+
+    for signature in config.signatures {
+        let pattern: Vec<_> = signature
+            .pattern
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        println!(
+            "{}\t@{}\t{}\t{}",
+            signature.name,
+            signature.offset,
+            pattern.join(" "),
+            signature.extensions.join(",")
+        );
+    }
+    Ok(Summary::default())
+}
+
+fn warn_rename(from: &Path, renamed: &Renamed) {
+    match renamed {
+        Renamed::To(_) => {}
+        Renamed::Suffixed { taken, to } => eprintln!(
+            "warning: {} exists; renaming {} to {}",
+            display_filename(taken),
+            display_filename(from),
+            display_filename(to)
+        ),
+        Renamed::Overwrote(to) => eprintln!("warning: overwrote {}", display_filename(to)),
+        Renamed::Skipped { taken } => eprintln!(
+            "warning: skipped {}: {} exists",
+            display_filename(from),
+            display_filename(taken)
+        ),
+    }
+}
+
+fn display_filename(path: &Path) -> path::Display<'_> {
+    Path::new(path.file_name().unwrap_or(path.as_os_str())).display()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use clap::Parser;
+    use imgfix::Error;
+
+    use super::{
+        Args, Outcome, Record, Session, Summary, EXIT_CLEAN, EXIT_ERRORS, EXIT_MISMATCHES,
+    };
+
+    fn failure() -> Error {
+        io::Error::from(io::ErrorKind::NotFound).into()
+    }
+
+    fn summary(outcomes: &[Outcome], failures: usize) -> Summary {
+        let mut summary = Summary::default();
+        for &outcome in outcomes {
+            summary.add(outcome);
+        }
+        for _ in 0..failures {
+            summary.fail(failure());
+        }
+        summary
+    }
+
+    #[test]
+    fn exit_status_reflects_the_worst_outcome() {
+        assert_eq!(summary(&[], 0).finish(), EXIT_CLEAN);
+        assert_eq!(summary(&[Outcome::Clean], 0).finish(), EXIT_CLEAN);
+        assert_eq!(
+            summary(&[Outcome::Clean, Outcome::Mismatch], 0).finish(),
+            EXIT_MISMATCHES
+        );
+        assert_eq!(summary(&[Outcome::Missing], 0).finish(), EXIT_MISMATCHES);
+        assert_eq!(summary(&[Outcome::Mismatch], 1).finish(), EXIT_ERRORS);
+        assert_eq!(summary(&[], 1).finish(), EXIT_ERRORS);
+    }
+
+    #[test]
+    fn failures_only_stop_the_run_with_fail_fast() {
+        let record = || Record::error(None, &failure());
+
+        let args = Args::parse_from(["imgfix", "a.png"]);
+        let mut session = Session::new(&args);
+        assert!(session.fail(record(), failure()).is_ok());
+        assert!(session.fail(record(), failure()).is_ok());
+        let summary = session.finish().unwrap();
+        assert_eq!((summary.checked, summary.errors.len()), (2, 2));
+        assert_eq!(summary.finish(), EXIT_ERRORS);
+
+        let args = Args::parse_from(["imgfix", "--fail-fast", "a.png"]);
+        let mut session = Session::new(&args);
+        assert!(session.fail(record(), failure()).is_err());
+
+        let args = Args::parse_from(["imgfix", "--fail-fast", "--keep-going", "a.png"]);
+        let mut session = Session::new(&args);
+        assert!(session.fail(record(), failure()).is_ok());
+    }
 }
@@ -0,0 +1,273 @@
+use std::{fmt, fs, io, path::Path, str::FromStr};
+
+use filetime::FileTime;
+
+use crate::Result;
+
+/// Which metadata to carry over when imgfix writes a file in place of another.
+///
+/// Renames keep everything on their own; this is for the paths that write new files, such as
+/// conversions, archive rewrites, copies and moves to another filesystem.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Preserve {
+    /// access and modification times
+    pub timestamps: bool,
+    /// permission bits
+    pub mode: bool,
+    /// extended attributes, where the platform has them
+    pub xattr: bool,
+    /// user and group, which usually takes root
+    pub owner: bool,
+}
+
+impl Preserve {
+    /// Keep nothing; new files get the usual defaults.
+    pub const NONE: Preserve = Preserve {
+        timestamps: false,
+        mode: false,
+        xattr: false,
+        owner: false,
+    };
+
+    /// Keep everything, including the owner.
+    pub const ALL: Preserve = Preserve {
+        timestamps: true,
+        mode: true,
+        xattr: true,
+        owner: true,
+    };
+
+    /// Copies the chosen metadata of `from` onto `to`.
+    ///
+    /// Timestamps go last, since setting anything else may count as a change.
+    pub fn copy(self, from: &Path, to: &Path) -> Result<()> {
+        if self == Preserve::NONE {
+            return Ok(());
+        }
+        let metadata = fs::metadata(from)?;
+        if self.xattr {
+            copy_xattrs(from, to)?;
+        }
+        // Changing the owner can clear setuid bits, so it comes before the mode.
+        if self.owner {
+            copy_owner(&metadata, to)?;
+        }
+        if self.mode {
+            fs::set_permissions(to, metadata.permissions())?;
+        }
+        if self.timestamps {
+            let accessed = FileTime::from_last_access_time(&metadata);
+            let modified = FileTime::from_last_modification_time(&metadata);
+            filetime::set_file_times(to, accessed, modified)?;
+        }
+        Ok(())
+    }
+}
+
+/// Timestamps, mode and extended attributes; owners are left to `--preserve=owner`.
+impl Default for Preserve {
+    fn default() -> Self {
+        Preserve {
+            owner: false,
+            ..Preserve::ALL
+        }
+    }
+}
+
+/// Parses a list such as `timestamps,mode`, or `all` or `none`.
+impl FromStr for Preserve {
+    type Err = String;
+
+    fn from_str(list: &str) -> Result<Self, String> {
+        let mut preserve = Preserve::NONE;
+        for name in list.split(',').map(str::trim) {
+            match name {
+                "timestamps" => preserve.timestamps = true,
+                "mode" => preserve.mode = true,
+                "xattr" => preserve.xattr = true,
+                "owner" => preserve.owner = true,
+                "all" => preserve = Preserve::ALL,
+                "none" => {}
+                name => {
+                    return Err(format!(
+                        "unknown attribute {name}; expected timestamps, mode, xattr, owner, all or none"
+                    ))
+                }
+            }
+        }
+        Ok(preserve)
+    }
+}
+
+impl fmt::Display for Preserve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [
+            (self.timestamps, "timestamps"),
+            (self.mode, "mode"),
+            (self.xattr, "xattr"),
+            (self.owner, "owner"),
+        ];
+        let names: Vec<_> = names
+            .into_iter()
+            .filter_map(|(on, name)| on.then_some(name))
+            .collect();
+        match names.is_empty() {
+            true => f.write_str("none"),
+            false => f.write_str(&names.join(",")),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn copy_xattrs(from: &Path, to: &Path) -> io::Result<()> {
+    let names = match xattr::list(from) {
+        Ok(names) => names,
+        // Nothing to copy from a filesystem without them.
+        Err(e) if is_unsupported(&e) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    for name in names {
+        if let Some(value) = xattr::get(from, &name)? {
+            match xattr::set(to, &name, &value) {
+                // Nowhere to copy them to on a filesystem without them.
+                Err(e) if is_unsupported(&e) => return Ok(()),
+                result => result?,
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn copy_xattrs(_from: &Path, _to: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_unsupported(e: &io::Error) -> bool {
+    // The two are the same on Linux but not on macOS or the BSDs.
+    let code = e.raw_os_error();
+    e.kind() == io::ErrorKind::Unsupported
+        || code == Some(libc::ENOTSUP)
+        || code == Some(libc::EOPNOTSUPP)
+}
+
+#[cfg(unix)]
+fn copy_owner(metadata: &fs::Metadata, to: &Path) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    std::os::unix::fs::chown(to, Some(metadata.uid()), Some(metadata.gid()))
+}
+
+#[cfg(not(unix))]
+fn copy_owner(_metadata: &fs::Metadata, _to: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use filetime::FileTime;
+
+    use super::Preserve;
+    use crate::rename;
+
+    fn times(path: &Path) -> (FileTime, FileTime) {
+        let metadata = fs::metadata(path).unwrap();
+        (
+            FileTime::from_last_access_time(&metadata),
+            FileTime::from_last_modification_time(&metadata),
+        )
+    }
+
+    /// A file with unusual metadata, and whether the filesystem took an extended attribute.
+    fn original(dir: &Path) -> (std::path::PathBuf, bool) {
+        let path = dir.join("original.png");
+        fs::write(&path, b"original").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        }
+        let has_xattr = set_xattr(&path);
+        let accessed = FileTime::from_unix_time(1_000_000_000, 0);
+        let modified = FileTime::from_unix_time(1_234_567_890, 500);
+        filetime::set_file_times(&path, accessed, modified).unwrap();
+        (path, has_xattr)
+    }
+
+    #[cfg(unix)]
+    fn set_xattr(path: &Path) -> bool {
+        xattr::set(path, "user.imgfix.test", b"kept").is_ok()
+    }
+
+    #[cfg(not(unix))]
+    fn set_xattr(_path: &Path) -> bool {
+        false
+    }
+
+    #[cfg(unix)]
+    fn assert_xattr(path: &Path) {
+        let value = xattr::get(path, "user.imgfix.test").unwrap();
+        assert_eq!(value.as_deref(), Some(&b"kept"[..]));
+    }
+
+    #[cfg(not(unix))]
+    fn assert_xattr(_path: &Path) {}
+
+    #[test]
+    fn metadata_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, has_xattr) = original(dir.path());
+        let to = dir.path().join("copy.png");
+        fs::write(&to, b"copy").unwrap();
+
+        Preserve::default().copy(&from, &to).unwrap();
+
+        assert_eq!(times(&to), times(&from));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&to).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o640);
+        }
+        if has_xattr {
+            assert_xattr(&to);
+        }
+    }
+
+    #[test]
+    fn nothing_is_copied_unless_asked() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, _) = original(dir.path());
+        let to = dir.path().join("copy.png");
+        fs::write(&to, b"copy").unwrap();
+        let before = times(&to);
+
+        Preserve::NONE.copy(&from, &to).unwrap();
+        assert_eq!(times(&to).1, before.1);
+    }
+
+    #[test]
+    fn replaced_files_keep_their_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _) = original(dir.path());
+        let before = times(&path);
+
+        rename::write_file(&path, b"rewritten", Preserve::default()).unwrap();
+        // Reading may bump the access time, so check the times first.
+        assert_eq!(times(&path), before);
+        assert_eq!(fs::read(&path).unwrap(), b"rewritten");
+    }
+
+    #[test]
+    fn lists_are_parsed() {
+        let preserve: Preserve = "mode, owner".parse().unwrap();
+        assert_eq!(preserve.to_string(), "mode,owner");
+        assert_eq!("none".parse::<Preserve>().unwrap(), Preserve::NONE);
+        assert_eq!("all".parse::<Preserve>().unwrap(), Preserve::ALL);
+        assert!("mtime".parse::<Preserve>().is_err());
+    }
+}
@@ -0,0 +1,393 @@
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use serde::{Serialize, Serializer};
+
+use crate::display_filename;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// one line per proposed or completed rename
+    #[default]
+    Text,
+    /// a single JSON array, written when the run finishes
+    Json,
+    /// one JSON object per line
+    Ndjson,
+    /// comma-separated values with a header row
+    Csv,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    None,
+    WouldRename,
+    Renamed,
+    Copied,
+    Skipped,
+    Error,
+    WouldQuarantine,
+    Quarantined,
+    WouldConvert,
+    Converted,
+}
+
+impl Action {
+    fn as_str(self) -> &'static str {
+        match self {
+            Action::None => "none",
+            Action::WouldRename => "would-rename",
+            Action::Renamed => "renamed",
+            Action::Copied => "copied",
+            Action::Skipped => "skipped",
+            Action::Error => "error",
+            Action::WouldQuarantine => "would-quarantine",
+            Action::Quarantined => "quarantined",
+            Action::WouldConvert => "would-convert",
+            Action::Converted => "converted",
+        }
+    }
+}
+
+/// What happened to a single file.
+#[derive(Debug, Serialize)]
+pub struct Record {
+    #[serde(serialize_with = "lossy")]
+    pub path: Option<PathBuf>,
+    pub extension: Option<String>,
+    #[serde(serialize_with = "format_name")]
+    pub format: Option<imgfix::Format>,
+    pub proposed: Option<&'static str>,
+    pub action: Action,
+    #[serde(serialize_with = "lossy")]
+    pub target: Option<PathBuf>,
+    pub error: Option<String>,
+}
+
+impl Record {
+    pub fn new(path: &Path, format: imgfix::Format) -> Self {
+        Record {
+            path: Some(path.into()),
+            extension: extension(path),
+            format: Some(format),
+            proposed: None,
+            action: Action::None,
+            target: None,
+            error: None,
+        }
+    }
+
+    pub fn error(path: Option<&Path>, error: &dyn std::error::Error) -> Self {
+        Record {
+            path: path.map(Into::into),
+            extension: path.and_then(extension),
+            format: None,
+            proposed: None,
+            action: Action::Error,
+            target: None,
+            error: Some(error.to_string()),
+        }
+    }
+
+    /// Takes the extension from an archive entry's `name`, since the path shown for it,
+    /// `photos.zip!name`, would give the archive's.
+    pub fn entry(mut self, name: &str) -> Self {
+        self.extension = extension(Path::new(name));
+        self
+    }
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .filter(|extension| !extension.is_empty())
+        .map(|extension| extension.to_string_lossy().into_owned())
+}
+
+/// Writes records to stdout in the requested format.
+#[derive(Debug)]
+pub struct Report {
+    format: Format,
+    pending: Vec<Record>,
+    started: bool,
+}
+
+impl Report {
+    pub fn new(format: Format) -> Self {
+        Report {
+            format,
+            pending: Vec::new(),
+            started: false,
+        }
+    }
+
+    pub fn record(&mut self, record: Record) -> io::Result<()> {
+        self.write(&mut io::stdout().lock(), record)
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.end(&mut io::stdout().lock())
+    }
+
+    fn write(&mut self, out: &mut impl Write, record: Record) -> io::Result<()> {
+        match self.format {
+            Format::Text => write_text(out, &record)?,
+            Format::Json => self.pending.push(record),
+            Format::Ndjson => {
+                serde_json::to_writer(&mut *out, &record)?;
+                writeln!(out)?;
+            }
+            Format::Csv => {
+                if !self.started {
+                    writeln!(out, "{CSV_HEADER}")?;
+                }
+                write_csv(out, &record)?;
+            }
+        }
+        self.started = true;
+        Ok(())
+    }
+
+    fn end(self, out: &mut impl Write) -> io::Result<()> {
+        match self.format {
+            Format::Json => {
+                serde_json::to_writer_pretty(&mut *out, &self.pending)?;
+                writeln!(out)
+            }
+            // The header still says what each column would have held.
+            Format::Csv if !self.started => writeln!(out, "{CSV_HEADER}"),
+            _ => Ok(()),
+        }
+    }
+}
+
+const CSV_HEADER: &str = "path,extension,format,proposed,action,target,error";
+
+fn write_text(out: &mut impl Write, record: &Record) -> io::Result<()> {
+    if let (Action::WouldQuarantine | Action::Quarantined, Some(path), Some(target)) =
+        (record.action, &record.path, &record.target)
+    {
+        return match record.action {
+            Action::Quarantined => writeln!(out, "{}", target.display()),
+            _ => writeln!(out, "{} -> {}", display_filename(path), target.display()),
+        };
+    }
+
+    if let (Action::WouldConvert | Action::Converted, Some(path), Some(format)) =
+        (record.action, &record.path, record.format)
+    {
+        let verb = match record.action {
+            Action::Converted => "converted",
+            _ => "would convert",
+        };
+        return writeln!(out, "{}: {verb} from {format}", display_filename(path));
+    }
+
+    let Some(path) = &record.path else {
+        return Ok(());
+    };
+
+    // Files sent to another directory are shown with the whole path they went to.
+    let target = record
+        .target
+        .as_deref()
+        .map(|target| match target.parent() == path.parent() {
+            true => display_filename(target),
+            false => target.display(),
+        });
+
+    match (record.action, target, record.proposed) {
+        (Action::WouldRename, Some(target), _) => {
+            writeln!(out, "{} -> {target}", display_filename(path))
+        }
+        (Action::WouldRename, None, Some(proposed)) if record.extension.is_none() => writeln!(
+            out,
+            "{} (no extension) -> {proposed}",
+            display_filename(path)
+        ),
+        (Action::WouldRename, None, Some(proposed)) => {
+            writeln!(out, "{} -> {proposed}", display_filename(path))
+        }
+        (Action::Renamed | Action::Copied, Some(target), _) => writeln!(out, "{target}"),
+        _ => Ok(()),
+    }
+}
+
+/// Writes the lines `rewrite` changes as a unified diff.
+pub fn write_diff(out: &mut impl Write, rewrite: &imgfix::refs::Rewrite) -> io::Result<()> {
+    let path = rewrite.path.display();
+    writeln!(out, "--- a/{path}\n+++ b/{path}")?;
+
+    // References never span lines, so the two texts line up one to one.
+    let lines = rewrite.before.lines().zip(rewrite.after.lines());
+    for (idx, (before, after)) in lines.enumerate() {
+        if before != after {
+            writeln!(out, "@@ -{0} +{0} @@\n-{before}\n+{after}", idx + 1)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_csv(out: &mut impl Write, record: &Record) -> io::Result<()> {
+    let path = |path: &Option<PathBuf>| {
+        path.as_ref()
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+    let format = record.format.map(|f| f.to_string()).unwrap_or_default();
+
+    let fields = [
+        &*path(&record.path),
+        record.extension.as_deref().unwrap_or_default(),
+        &format,
+        record.proposed.unwrap_or_default(),
+        record.action.as_str(),
+        &path(&record.target),
+        record.error.as_deref().unwrap_or_default(),
+    ];
+
+    for (idx, field) in fields.iter().enumerate() {
+        if idx > 0 {
+            out.write_all(b",")?;
+        }
+        write_csv_field(out, field)?;
+    }
+    writeln!(out)
+}
+
+fn write_csv_field(out: &mut impl Write, field: &str) -> io::Result<()> {
+    if field.contains([',', '"', '\n', '\r']) {
+        write!(out, "\"{}\"", field.replace('"', "\"\""))
+    } else {
+        out.write_all(field.as_bytes())
+    }
+}
+
+fn lossy<S: Serializer>(path: &Option<PathBuf>, serializer: S) -> Result<S::Ok, S::Error> {
+    match path {
+        Some(path) => serializer.serialize_str(&path.to_string_lossy()),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn format_name<S: Serializer>(
+    format: &Option<imgfix::Format>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match format {
+        Some(format) => serializer.collect_str(format),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use image::ImageFormat;
+    use serde_json::json;
+
+    use super::{write_csv_field, write_text, Action, Format, Record, Report};
+
+    fn would_rename() -> Record {
+        let mut record = Record::new(
+            Path::new("in/a, b.png"),
+            imgfix::Format::Image(ImageFormat::Jpeg),
+        );
+        record.proposed = Some("jpg");
+        record.action = Action::WouldRename;
+        record
+    }
+
+    fn report(format: Format, records: impl IntoIterator<Item = Record>) -> String {
+        let mut out = Vec::new();
+        let mut report = Report::new(format);
+        for record in records {
+            report.write(&mut out, record).unwrap();
+        }
+        report.end(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        let quoted = |field| {
+            let mut out = Vec::new();
+            write_csv_field(&mut out, field).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(quoted("a.png"), "a.png");
+        assert_eq!(quoted("a, b.png"), "\"a, b.png\"");
+        assert_eq!(quoted("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(quoted("two\nlines"), "\"two\nlines\"");
+    }
+
+    #[test]
+    fn records_are_written_in_each_format() {
+        let expected = json!({
+            "path": "in/a, b.png",
+            "extension": "png",
+            "format": "Jpeg",
+            "proposed": "jpg",
+            "action": "would-rename",
+            "target": null,
+            "error": null,
+        });
+        let ndjson: serde_json::Value =
+            serde_json::from_str(&report(Format::Ndjson, [would_rename()])).unwrap();
+        assert_eq!(ndjson, expected);
+        let json: serde_json::Value =
+            serde_json::from_str(&report(Format::Json, [would_rename()])).unwrap();
+        assert_eq!(json, json!([expected]));
+
+        assert_eq!(
+            report(Format::Csv, [would_rename()]),
+            "path,extension,format,proposed,action,target,error\n\
+             \"in/a, b.png\",png,Jpeg,jpg,would-rename,,\n"
+        );
+        assert_eq!(report(Format::Text, [would_rename()]), "a, b.png -> jpg\n");
+    }
+
+    #[test]
+    fn empty_reports_are_still_well_formed() {
+        assert_eq!(
+            report(Format::Csv, []),
+            "path,extension,format,proposed,action,target,error\n"
+        );
+        assert_eq!(report(Format::Json, []), "[]\n");
+        assert_eq!(report(Format::Ndjson, []), "");
+        assert_eq!(report(Format::Text, []), "");
+    }
+
+    #[test]
+    fn text_shows_what_happened() {
+        let text = |record: &Record| {
+            let mut out = Vec::new();
+            write_text(&mut out, record).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let mut missing = would_rename();
+        missing.path = Some("in/scan".into());
+        missing.extension = None;
+        assert_eq!(text(&missing), "scan (no extension) -> jpg\n");
+
+        let mut renamed = would_rename();
+        renamed.action = Action::Renamed;
+        renamed.target = Some("in/a, b.jpg".into());
+        assert_eq!(text(&renamed), "a, b.jpg\n");
+        renamed.target = Some(PathBuf::from("out/a, b.jpg"));
+        assert_eq!(text(&renamed), "out/a, b.jpg\n");
+
+        let mut quarantined = would_rename();
+        quarantined.action = Action::WouldQuarantine;
+        quarantined.target = Some("q/a, b.png".into());
+        assert_eq!(text(&quarantined), "a, b.png -> q/a, b.png\n");
+
+        let failed = Record::error(Some(Path::new("a.png")), &std::fmt::Error);
+        assert_eq!(text(&failed), "");
+    }
+}
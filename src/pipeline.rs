@@ -0,0 +1,161 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Mutex,
+    },
+    thread,
+};
+
+/// Runs `work` over `items` on up to `jobs` threads and hands each result to `sink`.
+///
+/// Items are pulled lazily, so only a bounded window of them is ever in flight. `sink` always
+/// runs on the calling thread, one result at a time; anything with side effects (renames, the
+/// journal, output) belongs there rather than in `work`. When `ordered` is set, results reach
+/// `sink` in the order their items were produced. The first error from `sink` stops the run.
+pub fn for_each<I, T, U, E>(
+    items: I,
+    jobs: usize,
+    ordered: bool,
+    work: impl Fn(T) -> U + Sync,
+    mut sink: impl FnMut(U) -> Result<(), E>,
+) -> Result<(), E>
+where
+    I: Iterator<Item = T>,
+    T: Send,
+    U: Send,
+{
+    if jobs <= 1 {
+        return items.map(work).try_for_each(sink);
+    }
+
+    let window = jobs * 4;
+    let cancelled = AtomicBool::new(false);
+    let (job_tx, job_rx) = mpsc::channel::<(usize, T)>();
+    let (result_tx, result_rx) = mpsc::channel::<(usize, U)>();
+    let job_rx = Mutex::new(job_rx);
+
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            let result_tx = result_tx.clone();
+            let (job_rx, work, cancelled) = (&job_rx, &work, &cancelled);
+            scope.spawn(move || loop {
+                let job = job_rx.lock().unwrap().recv();
+                let Ok((idx, item)) = job else {
+                    break;
+                };
+                if cancelled.load(Ordering::Relaxed) {
+                    break;
+                }
+                if result_tx.send((idx, work(item))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(result_tx);
+
+        let result = drive(items, window, ordered, &job_tx, &result_rx, &mut sink);
+        if result.is_err() {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+        drop(job_tx);
+        result
+    })
+}
+
+fn drive<I, T, U, E>(
+    mut items: I,
+    window: usize,
+    ordered: bool,
+    job_tx: &mpsc::Sender<(usize, T)>,
+    result_rx: &mpsc::Receiver<(usize, U)>,
+    sink: &mut impl FnMut(U) -> Result<(), E>,
+) -> Result<(), E>
+where
+    I: Iterator<Item = T>,
+{
+    let mut sent = 0;
+    let mut done = 0;
+    let mut exhausted = false;
+    let mut waiting = BTreeMap::new();
+
+    loop {
+        while !exhausted && sent - done < window {
+            match items.next() {
+                Some(item) => {
+                    job_tx
+                        .send((sent, item))
+                        .expect("workers outlive the job queue");
+                    sent += 1;
+                }
+                None => exhausted = true,
+            }
+        }
+
+        if sent == done {
+            return Ok(());
+        }
+
+        let (idx, result) = result_rx.recv().expect("a worker panicked");
+        if !ordered {
+            done += 1;
+            sink(result)?;
+            continue;
+        }
+
+        waiting.insert(idx, result);
+        while let Some(result) = waiting.remove(&done) {
+            done += 1;
+            sink(result)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use super::for_each;
+
+    #[test]
+    fn ordered_results_keep_their_input_order() {
+        let mut seen = Vec::new();
+        let work = |n: u64| {
+            // Later items finish first, so arrival order alone would come out reversed.
+            thread::sleep(Duration::from_millis(20 - n));
+            n
+        };
+        let sink = |n| {
+            seen.push(n);
+            Ok::<_, ()>(())
+        };
+        for_each(0..20, 4, true, work, sink).unwrap();
+        assert_eq!(seen, (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn sink_errors_stop_the_run() {
+        for jobs in [1, 4] {
+            let worked = AtomicUsize::new(0);
+            let mut sunk = 0;
+            let work = |n: usize| {
+                worked.fetch_add(1, Ordering::Relaxed);
+                n
+            };
+            let sink = |n| {
+                sunk += 1;
+                match n {
+                    10 => Err(n),
+                    _ => Ok(()),
+                }
+            };
+            assert_eq!(for_each(0..10_000, jobs, true, work, sink), Err(10));
+            assert_eq!(sunk, 11);
+            assert!(worked.into_inner() < 10_000);
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+
+use uncased::UncasedStr;
+
+use crate::{detect, Format};
+
+/// Per-format overrides for the extension files are renamed to.
+#[derive(Clone, Debug, Default)]
+pub struct Preferences {
+    preferred: HashMap<Format, &'static str>,
+}
+
+impl Preferences {
+    /// Prefers `extension` for `format`, provided it is one the format allows.
+    pub fn set(&mut self, format: Format, extension: &str) -> Result<(), String> {
+        let wanted: &UncasedStr = extension.trim_start_matches('.').into();
+        let extension = format
+            .extensions()
+            .iter()
+            .find(|&&ext| ext == wanted)
+            .ok_or_else(|| {
+                format!(
+                    "{extension} is not an extension for {format} (try {})",
+                    format.extensions().join(", ")
+                )
+            })?;
+
+        self.preferred.insert(format, extension);
+        Ok(())
+    }
+
+    /// The extension a file of this format should be given.
+    pub fn preferred_extension(&self, format: Format) -> &'static str {
+        self.preferred
+            .get(&format)
+            .copied()
+            .unwrap_or_else(|| detect::preferred_extension(format))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::ImageFormat;
+
+    use super::Preferences;
+    use crate::Format;
+
+    #[test]
+    fn preferences_must_be_allowed() {
+        let jpeg = Format::from_name("JPEG", &[]).unwrap();
+        let tiff = Format::from_name("tiff", &[]).unwrap();
+        assert_eq!(jpeg, Format::Image(ImageFormat::Jpeg));
+
+        let mut preferences = Preferences::default();
+        assert_eq!(preferences.preferred_extension(jpeg), "jpg");
+        preferences.set(jpeg, ".JPEG").unwrap();
+        preferences.set(tiff, "tif").unwrap();
+        assert_eq!(preferences.preferred_extension(jpeg), "jpeg");
+        assert_eq!(preferences.preferred_extension(tiff), "tif");
+
+        assert!(preferences.set(jpeg, "png").is_err());
+        assert_eq!(preferences.preferred_extension(jpeg), "jpeg");
+    }
+}
@@ -0,0 +1,294 @@
+//! Rewriting references to renamed images in HTML, Markdown and CSS.
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Component, Path, PathBuf},
+    sync::OnceLock,
+};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{rename, Error, Preserve, Result};
+
+/// One edit to a document's text.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Replacement {
+    /// byte offset of `old` in the text being edited
+    pub start: usize,
+    /// The text replaced.
+    pub old: String,
+    /// The text it was replaced with.
+    pub new: String,
+}
+
+/// The new contents of a document whose references were rewritten.
+#[derive(Debug)]
+pub struct Rewrite {
+    /// The document.
+    pub path: PathBuf,
+    /// Its text as read.
+    pub before: String,
+    /// Its text with the references rewritten.
+    pub after: String,
+    /// Edits to `before`, in order.
+    pub replacements: Vec<Replacement>,
+}
+
+/// Rewrites references to files that have been renamed.
+#[derive(Debug)]
+pub struct Rewriter {
+    renames: HashMap<PathBuf, PathBuf>,
+    root: PathBuf,
+}
+
+impl Rewriter {
+    /// `root` is the directory references such as `/img/hero.png` are relative to.
+    pub fn new(root: &Path) -> Result<Self> {
+        Ok(Rewriter {
+            renames: HashMap::new(),
+            root: absolute(root)?,
+        })
+    }
+
+    /// Records that `from` was renamed to `to`.
+    pub fn add(&mut self, from: &Path, to: &Path) -> Result<()> {
+        self.renames.insert(absolute(from)?, absolute(to)?);
+        Ok(())
+    }
+
+    /// Whether no renames have been recorded, so there's nothing to rewrite.
+    pub fn is_empty(&self) -> bool {
+        self.renames.is_empty()
+    }
+
+    /// Reads the document at `path` and rewrites its references, if it has any to change.
+    pub fn rewrite_file(&self, path: &Path) -> Result<Option<Rewrite>> {
+        let before = fs::read_to_string(path).map_err(|e| Error::from(e).with_path(path))?;
+        let mut dir = absolute(path)?;
+        dir.pop();
+        let replacements = self.rewrite(&dir, &before);
+        if replacements.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Rewrite {
+            path: path.into(),
+            after: apply(&before, &replacements),
+            before,
+            replacements,
+        }))
+    }
+
+    /// Finds the references in `text` that need rewriting, for a document in `dir`.
+    pub fn rewrite(&self, dir: &Path, text: &str) -> Vec<Replacement> {
+        let mut replacements: Vec<_> = patterns()
+            .iter()
+            .flat_map(|pattern| pattern.captures_iter(text))
+            .filter_map(|captures| captures.iter().skip(1).flatten().next())
+            .filter_map(|found| {
+                let new = self.rewrite_reference(dir, found.as_str())?;
+                Some(Replacement {
+                    start: found.start(),
+                    old: found.as_str().into(),
+                    new,
+                })
+            })
+            .collect();
+
+        replacements.sort_by_key(|replacement| replacement.start);
+        replacements.dedup_by_key(|replacement| replacement.start);
+        replacements
+    }
+
+    fn rewrite_reference(&self, dir: &Path, reference: &str) -> Option<String> {
+        if reference.is_empty() || reference.starts_with("//") || has_scheme(reference) {
+            return None;
+        }
+
+        let end = reference.find(['?', '#']).unwrap_or(reference.len());
+        let (path, rest) = reference.split_at(end);
+        let decoded = percent_decode(path)?;
+        let resolved = match decoded.strip_prefix('/') {
+            Some(path) => self.root.join(path),
+            None => dir.join(&decoded),
+        };
+        let to = self.renames.get(&normalize(&resolved))?;
+
+        // Only the file name changes, so keep the rest of the reference as it was written.
+        let (dir, name) = path.split_at(path.rfind('/').map_or(0, |idx| idx + 1));
+        let new_name = to.file_name()?.to_str()?;
+        let new_name = match percent_decode(name)? == name {
+            true => new_name.into(),
+            false => percent_encode(new_name),
+        };
+        Some(format!("{dir}{new_name}{rest}"))
+    }
+}
+
+impl Rewrite {
+    /// Replaces the document with its rewritten text.
+    pub fn save(&self, preserve: Preserve) -> Result<()> {
+        rename::write_file(&self.path, self.after.as_bytes(), preserve)
+            .map_err(|e| e.with_path(&self.path))
+    }
+
+    /// The edits that turn `after` back into `before`.
+    pub fn reversed(&self) -> Vec<Replacement> {
+        let mut shift = 0isize;
+        self.replacements
+            .iter()
+            .map(|replacement| {
+                let start = replacement.start.checked_add_signed(shift).unwrap();
+                shift += replacement.new.len() as isize - replacement.old.len() as isize;
+                Replacement {
+                    start,
+                    old: replacement.new.clone(),
+                    new: replacement.old.clone(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Applies `replacements`, which must be in order and not overlap, to `text`.
+pub fn apply(text: &str, replacements: &[Replacement]) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut copied = 0;
+    for replacement in replacements {
+        out.push_str(&text[copied..replacement.start]);
+        out.push_str(&replacement.new);
+        copied = replacement.start + replacement.old.len();
+    }
+    out.push_str(&text[copied..]);
+    out
+}
+
+/// Whether every replacement's `old` text is still where it was.
+pub fn applies(text: &str, replacements: &[Replacement]) -> bool {
+    replacements.iter().all(|replacement| {
+        let end = replacement.start + replacement.old.len();
+        text.get(replacement.start..end) == Some(replacement.old.as_str())
+    })
+}
+
+/// HTML `src` and `href` attributes, Markdown link targets and CSS `url()`s.
+fn patterns() -> &'static [Regex] {
+    static PATTERNS: OnceLock<Vec<Regex>> = OnceLock::new();
+    PATTERNS.get_or_init(|| {
+        [
+            r#"(?i)\b(?:src|href)\s*=\s*(?:"([^"\n]*)"|'([^'\n]*)')"#,
+            r"\]\(\s*<?([^)\s>]+)",
+            r#"(?i)\burl\(\s*(?:"([^"\n]*)"|'([^'\n]*)'|([^)'"\s]+))"#,
+        ]
+        .into_iter()
+        .map(|pattern| Regex::new(pattern).unwrap())
+        .collect()
+    })
+}
+
+/// Whether a reference is a URL such as `https://...` or `data:...` rather than a path.
+fn has_scheme(reference: &str) -> bool {
+    reference.split_once(':').is_some_and(|(scheme, _)| {
+        scheme.len() > 1
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    })
+}
+
+fn absolute(path: &Path) -> Result<PathBuf> {
+    Ok(normalize(&env::current_dir()?.join(path)))
+}
+
+/// Resolves `.` and `..` without touching the filesystem, since renamed files may not exist yet.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            component => out.push(component),
+        }
+    }
+    out
+}
+
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'%' {
+            let hex = text.get(idx + 1..idx + 3)?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            idx += 3;
+        } else {
+            out.push(bytes[idx]);
+            idx += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{apply, Rewrite, Rewriter};
+
+    #[test]
+    fn references_are_rewritten() {
+        let mut rewriter = Rewriter::new(Path::new("/site")).unwrap();
+        rewriter
+            .add(
+                Path::new("/site/img/hero.png"),
+                Path::new("/site/img/hero.jpg"),
+            )
+            .unwrap();
+        rewriter
+            .add(
+                Path::new("/site/img/a b.gif"),
+                Path::new("/site/img/a b (1).png"),
+            )
+            .unwrap();
+
+        let text = concat!(
+            "<img src=\"../img/hero.png\"> <a href='/img/hero.png#top'>\n",
+            "![hero](../img/hero.png \"Hero\") [other](../img/hero.pngx)\n",
+            "div { background: url(../img/a%20b.gif?v=2) } https://x.test/img/hero.png\n",
+        );
+        let replacements = rewriter.rewrite(Path::new("/site/docs"), text);
+        let after = apply(text, &replacements);
+        assert_eq!(
+            after,
+            concat!(
+                "<img src=\"../img/hero.jpg\"> <a href='/img/hero.jpg#top'>\n",
+                "![hero](../img/hero.jpg \"Hero\") [other](../img/hero.pngx)\n",
+                "div { background: url(../img/a%20b%20%281%29.png?v=2) } https://x.test/img/hero.png\n",
+            )
+        );
+
+        let rewrite = Rewrite {
+            path: "index.html".into(),
+            before: text.into(),
+            after: after.clone(),
+            replacements,
+        };
+        assert_eq!(apply(&after, &rewrite.reversed()), text);
+    }
+}
@@ -0,0 +1,414 @@
+//! Renaming, moving and rewriting files without losing data to a taken name or a crash.
+
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+
+use crate::{Error, Preserve, Result};
+
+/// What to do when the corrected name of a file is already taken.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Conflict {
+    /// leave the file alone
+    #[default]
+    Skip,
+    /// pick a free name such as `a (1).jpg`
+    Suffix,
+    /// replace the existing file
+    Overwrite,
+    /// stop with an error
+    Fail,
+}
+
+/// What became of a file asked to take a new name.
+#[derive(Debug)]
+pub enum Renamed {
+    /// The file was renamed to its preferred name.
+    To(PathBuf),
+    /// The preferred name was taken; the file was renamed to a free variant instead.
+    Suffixed {
+        /// The preferred name.
+        taken: PathBuf,
+        /// The name the file was given.
+        to: PathBuf,
+    },
+    /// The preferred name was taken and replaced.
+    Overwrote(PathBuf),
+    /// The preferred name was taken; the file was left alone.
+    Skipped {
+        /// The preferred name.
+        taken: PathBuf,
+    },
+}
+
+impl Renamed {
+    /// The path the file now lives at, if it was moved.
+    pub fn target(&self) -> Option<&Path> {
+        match self {
+            Renamed::To(to) | Renamed::Suffixed { to, .. } | Renamed::Overwrote(to) => Some(to),
+            Renamed::Skipped { .. } => None,
+        }
+    }
+}
+
+/// Renames `from` to `to` without clobbering an existing file unless asked to.
+///
+/// `to` may be on another filesystem, in which case the file is copied, with the metadata to
+/// `preserve`, and then removed.
+pub fn rename(from: &Path, to: PathBuf, conflict: Conflict, preserve: Preserve) -> Result<Renamed> {
+    place(from, to, conflict, |from, to| move_file(from, to, preserve))
+}
+
+/// Copies `from` to `to` without clobbering an existing file unless asked to, leaving `from`
+/// as it was.
+pub fn copy(from: &Path, to: PathBuf, conflict: Conflict, preserve: Preserve) -> Result<Renamed> {
+    place(from, to, conflict, |from, to| copy_file(from, to, preserve))
+}
+
+fn place(
+    from: &Path,
+    to: PathBuf,
+    conflict: Conflict,
+    transfer: impl FnOnce(&Path, &Path) -> Result<()>,
+) -> Result<Renamed> {
+    if !taken(&to, from)? {
+        transfer(from, &to)?;
+        return Ok(Renamed::To(to));
+    }
+
+    match conflict {
+        Conflict::Skip => Ok(Renamed::Skipped { taken: to }),
+        Conflict::Fail => Err(Error::conflict(to)),
+        Conflict::Overwrite => {
+            transfer(from, &to)?;
+            Ok(Renamed::Overwrote(to))
+        }
+        Conflict::Suffix => {
+            let free = free_name(&to)?;
+            transfer(from, &free)?;
+            Ok(Renamed::Suffixed {
+                taken: to,
+                to: free,
+            })
+        }
+    }
+}
+
+/// Finds the first of `a (1).jpg`, `a (2).jpg`, ... that does not exist.
+pub(crate) fn free_name(path: &Path) -> Result<PathBuf> {
+    let stem = path.file_stem().unwrap_or_default();
+    for n in 1.. {
+        let mut name = stem.to_owned();
+        name.push(format!(" ({n})"));
+        if let Some(extension) = path.extension() {
+            name.push(".");
+            name.push(extension);
+        }
+        let candidate = path.with_file_name(name);
+        if !exists(&candidate)? {
+            return Ok(candidate);
+        }
+    }
+
+    unreachable!("ran out of suffixes")
+}
+
+/// Whether `path` is occupied by something other than `from` itself.
+///
+/// On case-insensitive filesystems `IMG.jpg` already "exists" when renaming `IMG.JPG`, but only
+/// because it names the very file being renamed.
+pub(crate) fn taken(path: &Path, from: &Path) -> Result<bool> {
+    Ok(exists(path)? && !same_file::is_same_file(path, from)?)
+}
+
+/// Renames `from` to `to`, going by way of a temporary name when both name the same file.
+///
+/// Some case-insensitive filesystems treat a rename that only changes case as a no-op. Moves
+/// to another filesystem fall back to [`copy_file`].
+pub(crate) fn move_file(from: &Path, to: &Path, preserve: Preserve) -> Result<()> {
+    if !exists(to)? || !same_file::is_same_file(from, to)? {
+        return match fs::rename(from, to) {
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                copy_file(from, to, preserve)?;
+                Ok(fs::remove_file(from)?)
+            }
+            result => Ok(result?),
+        };
+    }
+
+    // A rename would replace anything already at the temporary name.
+    let mut temporaries = temporary_names(from);
+    let temporary = loop {
+        let temporary = temporaries.next().expect("names run out");
+        if !exists(&temporary)? {
+            break temporary;
+        }
+    };
+    fs::rename(from, &temporary)?;
+
+    // If `to` survived, it was a hard link to `from` rather than another spelling of its name.
+    if exists(to)? {
+        fs::remove_file(&temporary)?;
+        return Ok(());
+    }
+
+    if let Err(e) = fs::rename(&temporary, to) {
+        // Put things back the way they were rather than strand the file under a temporary name.
+        fs::rename(&temporary, from)?;
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+const TEMPORARY: &str = ".imgfix-tmp";
+
+/// Names beside `path` for a temporary file: `<name>.imgfix-tmp`, then `<name>.imgfix-tmp1` and
+/// so on.
+fn temporary_names(path: &Path) -> impl Iterator<Item = PathBuf> + '_ {
+    (0u64..).map(|n| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(TEMPORARY);
+        if n > 0 {
+            name.push(n.to_string());
+        }
+        name.into()
+    })
+}
+
+/// Whether `path` names a temporary file imgfix writes while replacing or renaming a file.
+pub fn is_temporary(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().as_encoded_bytes();
+    let mark = TEMPORARY.as_bytes();
+    match name.windows(mark.len()).rposition(|window| window == mark) {
+        Some(at) => name[at + mark.len()..].iter().all(u8::is_ascii_digit),
+        None => false,
+    }
+}
+
+/// Replaces the contents of `path` by way of a temporary file, so that it's never left half
+/// written.
+pub(crate) fn write_file(path: &Path, contents: &[u8], preserve: Preserve) -> Result<()> {
+    replace_file(path, preserve, |file| Ok(file.write_all(contents)?))
+}
+
+/// Replaces `path` with whatever `write` puts in a temporary file beside it, once `write` has
+/// succeeded and the metadata to `preserve` has been copied over.
+pub(crate) fn replace_file(
+    path: &Path,
+    preserve: Preserve,
+    write: impl FnOnce(&mut fs::File) -> Result<()>,
+) -> Result<()> {
+    replace_with(path, path, preserve, write)
+}
+
+/// As [`replace_file`], but with the metadata of `source` rather than of `path` itself.
+fn replace_with(
+    path: &Path,
+    source: &Path,
+    preserve: Preserve,
+    write: impl FnOnce(&mut fs::File) -> Result<()>,
+) -> Result<()> {
+    let (temporary, mut file) = create_temporary(path)?;
+    let written = (|| {
+        write(&mut file)?;
+        file.sync_all()?;
+        drop(file);
+        preserve.copy(source, &temporary)?;
+        Ok(fs::rename(&temporary, path)?)
+    })();
+    if written.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    written
+}
+
+/// Creates a temporary file beside `path`, under the first of its [`temporary_names`] that's free.
+fn create_temporary(path: &Path) -> io::Result<(PathBuf, fs::File)> {
+    for temporary in temporary_names(path) {
+        let created = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&temporary);
+        match created {
+            Ok(file) => return Ok((temporary, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    unreachable!("ran out of temporary names")
+}
+
+/// Copies `from` to `to` by way of a temporary file, which is synced, compared with `from` and
+/// given its metadata before it takes the place of `to`; if any of that fails, `to` is left
+/// alone.
+pub(crate) fn copy_file(from: &Path, to: &Path, preserve: Preserve) -> Result<()> {
+    replace_with(to, from, preserve, |file| {
+        io::copy(&mut fs::File::open(from)?, file)?;
+        file.sync_all()?;
+        file.rewind()?;
+        if !same_contents(fs::File::open(from)?, &*file)? {
+            return Err(Error::copy_mismatch(to));
+        }
+        Ok(())
+    })?;
+    sync_dir(to)
+}
+
+fn same_contents(a: impl Read, b: impl Read) -> io::Result<bool> {
+    let (mut a, mut b) = (BufReader::new(a), BufReader::new(b));
+    loop {
+        let chunk = a.fill_buf()?;
+        if chunk.is_empty() {
+            return Ok(b.fill_buf()?.is_empty());
+        }
+        let len = chunk.len();
+        let mut other = vec![0; len];
+        match b.read_exact(&mut other) {
+            Ok(()) if other == chunk => a.consume(len),
+            Ok(()) => return Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Makes a new directory entry for `path` durable, where the platform allows it.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<()> {
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+    Ok(fs::File::open(dir.unwrap_or(Path::new(".")))?.sync_all()?)
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+// Broken symlinks count as taken, so `try_exists` alone won't do.
+pub(crate) fn exists(path: &Path) -> Result<bool> {
+    match fs::symlink_metadata(path) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use super::{
+        free_name, is_temporary, move_file, rename, replace_file, write_file, Conflict, Renamed,
+    };
+    use crate::{Error, Preserve};
+
+    /// `a.png` holding "new", and the taken `a.jpg` holding "old".
+    fn taken_name(dir: &Path) -> (PathBuf, PathBuf) {
+        let (from, to) = (dir.join("a.png"), dir.join("a.jpg"));
+        fs::write(&from, b"new").unwrap();
+        fs::write(&to, b"old").unwrap();
+        (from, to)
+    }
+
+    fn rename_into_taken(conflict: Conflict) -> (tempfile::TempDir, crate::Result<Renamed>) {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = taken_name(dir.path());
+        let renamed = rename(&from, to, conflict, Preserve::default());
+        (dir, renamed)
+    }
+
+    #[test]
+    fn taken_names_follow_the_conflict_policy() {
+        let (dir, renamed) = rename_into_taken(Conflict::default());
+        assert!(matches!(renamed.unwrap(), Renamed::Skipped { .. }));
+        assert_eq!(fs::read(dir.path().join("a.jpg")).unwrap(), b"old");
+        assert_eq!(fs::read(dir.path().join("a.png")).unwrap(), b"new");
+
+        let (dir, renamed) = rename_into_taken(Conflict::Fail);
+        assert!(matches!(renamed, Err(Error::Conflict(_))));
+        assert_eq!(fs::read(dir.path().join("a.jpg")).unwrap(), b"old");
+        assert!(dir.path().join("a.png").exists());
+
+        let (dir, renamed) = rename_into_taken(Conflict::Overwrite);
+        assert!(matches!(renamed.unwrap(), Renamed::Overwrote(_)));
+        assert_eq!(fs::read(dir.path().join("a.jpg")).unwrap(), b"new");
+        assert!(!dir.path().join("a.png").exists());
+    }
+
+    #[test]
+    fn suffixes_count_up_past_taken_names() {
+        let (dir, renamed) = rename_into_taken(Conflict::Suffix);
+        let first = dir.path().join("a (1).jpg");
+        assert!(matches!(renamed.unwrap(), Renamed::Suffixed { to, .. } if to == first));
+        assert_eq!(fs::read(&first).unwrap(), b"new");
+        assert_eq!(fs::read(dir.path().join("a.jpg")).unwrap(), b"old");
+
+        let (from, to) = taken_name(dir.path());
+        let renamed = rename(&from, to, Conflict::Suffix, Preserve::default()).unwrap();
+        assert_eq!(renamed.target(), Some(&*dir.path().join("a (2).jpg")));
+    }
+
+    #[test]
+    fn rewrites_leave_other_temporary_files_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.zip");
+        fs::write(&path, b"old").unwrap();
+        let unrelated = dir.path().join("a.zip.imgfix-tmp");
+        fs::write(&unrelated, b"someone else's").unwrap();
+        fs::create_dir(dir.path().join("a.zip.imgfix-tmp1")).unwrap();
+
+        write_file(&path, b"new", Preserve::default()).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(fs::read(&unrelated).unwrap(), b"someone else's");
+
+        let failed = replace_file(&path, Preserve::default(), |_| Err(Error::conflict(&path)));
+        assert!(failed.is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(fs::read(&unrelated).unwrap(), b"someone else's");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+
+        assert!(is_temporary(&unrelated));
+        assert!(is_temporary(Path::new("a.zip.imgfix-tmp12")));
+        assert!(!is_temporary(&path));
+        assert!(!is_temporary(Path::new("a.imgfix-tmp.png")));
+    }
+
+    #[test]
+    fn moving_onto_a_hard_link_drops_the_old_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = (dir.path().join("a.png"), dir.path().join("a.jpg"));
+        fs::write(&from, b"png").unwrap();
+        fs::hard_link(&from, &to).unwrap();
+        let unrelated = dir.path().join("a.png.imgfix-tmp");
+        fs::write(&unrelated, b"someone else's").unwrap();
+
+        move_file(&from, &to, Preserve::default()).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read(&to).unwrap(), b"png");
+        assert_eq!(fs::read(&unrelated).unwrap(), b"someone else's");
+        let leftovers = fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(leftovers, 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn suffixes_keep_names_that_are_not_utf8() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let taken = dir.path().join(OsStr::from_bytes(b"caf\xe9.png"));
+        fs::write(&taken, b"").unwrap();
+
+        let free = free_name(&taken).unwrap();
+        assert_eq!(free.file_name().unwrap().as_bytes(), b"caf\xe9 (1).png");
+    }
+}
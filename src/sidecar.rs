@@ -0,0 +1,96 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use uncased::UncasedStr;
+
+use crate::{rename, Result};
+
+/// Extensions of the metadata files photo tools keep beside an image.
+pub const DEFAULT_EXTENSIONS: &[&str] = &["xmp", "aae", "json", "thm"];
+
+/// Finds the sidecars of `path` with any of `extensions`, in either the `IMG_1.xmp` or the
+/// `IMG_1.png.xmp` form.
+pub(crate) fn find(path: &Path, extensions: &[String]) -> Result<Vec<PathBuf>> {
+    let mut found: Vec<PathBuf> = Vec::new();
+    for extension in extensions {
+        for extension in [
+            extension.to_ascii_lowercase(),
+            extension.to_ascii_uppercase(),
+        ] {
+            let mut name_form = path.as_os_str().to_owned();
+            name_form.push(".");
+            name_form.push(&extension);
+
+            for candidate in [path.with_extension(&extension), name_form.into()] {
+                // Case-insensitive filesystems find the same file under both spellings.
+                if rename::taken(&candidate, path)?
+                    && !found.iter().any(|seen| is_same(seen, &candidate))
+                {
+                    found.push(candidate);
+                }
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// The name `sidecar` should take now that its image has moved from `from` to `to`, if it
+/// needs a new one.
+///
+/// `IMG_1.png.json` always follows its image's name; `IMG_1.xmp` only changes when the stem
+/// does.
+pub(crate) fn target(sidecar: &Path, from: &Path, to: &Path) -> Option<PathBuf> {
+    let extension = sidecar.extension()?;
+    let base = match sidecar.file_stem()? == from.file_name()? {
+        true => to.file_name()?,
+        false => to.file_stem()?,
+    };
+
+    let mut name = OsString::from(base);
+    name.push(".");
+    name.push(extension);
+    let target = to.with_file_name(name);
+    (target != sidecar).then_some(target)
+}
+
+/// Whether `path` has one of the sidecar `extensions`.
+pub fn is_sidecar(path: &Path, extensions: &[String]) -> bool {
+    let Some(extension) = path.extension().and_then(|ext| ext.to_str()) else {
+        return false;
+    };
+    let extension = UncasedStr::new(extension);
+    extensions
+        .iter()
+        .any(|sidecar| extension == sidecar.as_str())
+}
+
+fn is_same(a: &Path, b: &Path) -> bool {
+    a == b || same_file::is_same_file(a, b).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::target;
+
+    fn moved(sidecar: &str, from: &str, to: &str) -> Option<PathBuf> {
+        target(Path::new(sidecar), Path::new(from), Path::new(to))
+    }
+
+    #[test]
+    fn sidecars_follow_their_image() {
+        let (from, to) = ("d/IMG_1.png", "d/IMG_1.jpg");
+        assert_eq!(
+            moved("d/IMG_1.png.json", from, to),
+            Some("d/IMG_1.jpg.json".into())
+        );
+        assert_eq!(moved("d/IMG_1.xmp", from, to), None);
+        assert_eq!(
+            moved("d/IMG_1.XMP", from, "d/IMG_1 (1).jpg"),
+            Some("d/IMG_1 (1).XMP".into())
+        );
+    }
+}
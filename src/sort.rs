@@ -0,0 +1,203 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fmt, fs,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    time::UNIX_EPOCH,
+};
+
+use crate::{Format, Result};
+
+/// Where `imgfix sort` puts each file, such as `{format}/{year}/{name}.{ext}`.
+///
+/// `{format}` is the detected format in lowercase, `{name}` the file name without its extension
+/// and `{ext}` the extension the contents call for. `{year}`, `{month}` and `{day}` come from
+/// the file's modification time, in UTC.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template {
+    text: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    Format,
+    Year,
+    Month,
+    Day,
+    Name,
+    Ext,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Field(Field),
+}
+
+impl Template {
+    /// Where the file at `path`, detected as `format` and due the extension `extension`, goes
+    /// under `into`.
+    pub fn target(
+        &self,
+        into: &Path,
+        path: &Path,
+        format: Format,
+        extension: &OsStr,
+    ) -> Result<PathBuf> {
+        let needs_date = self
+            .parts
+            .iter()
+            .any(|part| matches!(part, Part::Field(Field::Year | Field::Month | Field::Day)));
+        let date = match needs_date {
+            true => modified_date(path).map_err(|e| e.with_path(path))?,
+            false => (0, 0, 0),
+        };
+        let name = path.file_stem().unwrap_or_default();
+
+        let mut rendered = OsString::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => rendered.push(text),
+                Part::Field(Field::Format) => rendered.push(format.to_string().to_lowercase()),
+                Part::Field(Field::Year) => rendered.push(format!("{:04}", date.0)),
+                Part::Field(Field::Month) => rendered.push(format!("{:02}", date.1)),
+                Part::Field(Field::Day) => rendered.push(format!("{:02}", date.2)),
+                Part::Field(Field::Name) => rendered.push(name),
+                Part::Field(Field::Ext) => rendered.push(extension),
+            }
+        }
+
+        Ok(into.join(rendered))
+    }
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        "{format}/{name}.{ext}".parse().unwrap()
+    }
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut rest = text;
+        while let Some(open) = rest.find('{') {
+            if open > 0 {
+                parts.push(Part::Text(rest[..open].into()));
+            }
+            let close = rest[open..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed {{ in {text}"))?;
+            let field = match &rest[open + 1..open + close] {
+                "format" => Field::Format,
+                "year" => Field::Year,
+                "month" => Field::Month,
+                "day" => Field::Day,
+                "name" => Field::Name,
+                "ext" => Field::Ext,
+                name => {
+                    return Err(format!(
+                        "unknown placeholder {{{name}}}; expected format, year, month, day, name or ext"
+                    ))
+                }
+            };
+            parts.push(Part::Field(field));
+            rest = &rest[open + close + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.into()));
+        }
+
+        if parts.is_empty() {
+            return Err("empty template".into());
+        }
+        // Placeholders never contain a separator, so the template alone decides where files go.
+        let escapes = Path::new(text)
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir));
+        if escapes {
+            return Err(format!(
+                "{text} leaves the directory being sorted into; drop any .. or leading /"
+            ));
+        }
+        Ok(Template {
+            text: text.into(),
+            parts,
+        })
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// The year, month and day `path` was last modified, in UTC.
+fn modified_date(path: &Path) -> Result<(i64, u32, u32)> {
+    let modified = fs::metadata(path)?.modified()?;
+    let seconds = match modified.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs_f64().ceil() as i64),
+    };
+    Ok(civil_date(seconds.div_euclid(86_400)))
+}
+
+/// Converts days since 1970-01-01 to a proleptic Gregorian date.
+fn civil_date(days: i64) -> (i64, u32, u32) {
+    // After Howard Hinnant's `civil_from_days`, counting in 400-year eras from 0000-03-01.
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use filetime::FileTime;
+    use image::ImageFormat;
+
+    use super::{civil_date, Template};
+    use crate::Format;
+
+    #[test]
+    fn templates_are_rendered() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("IMG_1.png");
+        fs::write(&path, b"jpeg").unwrap();
+        // 2021-03-04 05:06:07 UTC
+        let modified = FileTime::from_unix_time(1_614_834_367, 0);
+        filetime::set_file_mtime(&path, modified).unwrap();
+
+        let template: Template = "{format}/{year}/{month}-{day}/{name}.{ext}"
+            .parse()
+            .unwrap();
+        let jpeg = Format::Image(ImageFormat::Jpeg);
+        let target = template.target(Path::new("out"), &path, jpeg, "jpg".as_ref());
+        assert_eq!(target.unwrap(), Path::new("out/jpeg/2021/03-04/IMG_1.jpg"));
+
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(-1), (1969, 12, 31));
+        assert_eq!(civil_date(11_016), (2000, 2, 29));
+        assert!("{format}/{size}".parse::<Template>().is_err());
+        assert!("{format".parse::<Template>().is_err());
+        assert!("{format}/../{name}.{ext}".parse::<Template>().is_err());
+        assert!("/{name}.{ext}".parse::<Template>().is_err());
+    }
+}
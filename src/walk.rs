@@ -0,0 +1,168 @@
+//! Finding the files to check, on the command line, on stdin or under directories.
+
+use std::{fs, io::BufRead, path::PathBuf};
+
+use walkdir::{DirEntry, WalkDir};
+
+use crate::{Error, Result};
+
+/// A file to be checked.
+#[derive(Clone, Debug)]
+pub struct Found {
+    /// Where the file is.
+    pub path: PathBuf,
+    /// The path under the directory it was found in, or just its name if it was named itself.
+    pub relative: PathBuf,
+}
+
+impl Found {
+    /// A file named directly rather than found by walking a directory.
+    pub fn named(path: PathBuf) -> Self {
+        Found {
+            relative: path.file_name().unwrap_or_default().into(),
+            path,
+        }
+    }
+}
+
+/// Expands command line paths into the regular files to be checked.
+#[derive(Clone, Debug)]
+pub struct Walker {
+    /// Walk into directories rather than skipping them.
+    pub recursive: bool,
+    /// How deep to walk; 1 is just a directory's own files.
+    pub max_depth: Option<usize>,
+    /// Follow symbolic links to directories.
+    pub follow_symlinks: bool,
+    /// Include files and directories whose names start with a dot.
+    pub include_hidden: bool,
+}
+
+impl Walker {
+    /// Expands `paths` lazily; errors reading the paths themselves are passed through.
+    ///
+    /// Unless walking recursively, a directory yields [`Error::SkippedDirectory`] in place of
+    /// its files, for the caller to report as it sees fit.
+    pub fn files<'a>(
+        &'a self,
+        paths: impl Iterator<Item = Result<PathBuf>> + 'a,
+    ) -> impl Iterator<Item = Result<Found>> + 'a {
+        paths.flat_map(move |path| match path {
+            Ok(path) => self.expand(path),
+            Err(e) => Box::new(Some(Err(e)).into_iter()),
+        })
+    }
+
+    fn expand(&self, path: PathBuf) -> Box<dyn Iterator<Item = Result<Found>> + '_> {
+        // Paths named explicitly are always checked as given; only directories need walking.
+        let is_dir = fs::metadata(&path)
+            .map(|meta| meta.is_dir())
+            .unwrap_or(false);
+        if !is_dir {
+            return Box::new(Some(Ok(Found::named(path))).into_iter());
+        }
+
+        if !self.recursive {
+            return Box::new(Some(Err(Error::SkippedDirectory(path))).into_iter());
+        }
+
+        let mut walk = WalkDir::new(&path)
+            .follow_links(self.follow_symlinks)
+            .sort_by_file_name();
+        if let Some(depth) = self.max_depth {
+            walk = walk.max_depth(depth);
+        }
+
+        let include_hidden = self.include_hidden;
+        let entries = walk
+            .into_iter()
+            .filter_entry(move |entry| include_hidden || entry.depth() == 0 || !is_hidden(entry))
+            .filter_map(move |entry| match entry {
+                Ok(entry) if entry.file_type().is_file() => {
+                    let relative = entry.path().strip_prefix(&path).unwrap_or(entry.path());
+                    Some(Ok(Found {
+                        relative: relative.into(),
+                        path: entry.into_path(),
+                    }))
+                }
+                Ok(_) => None,
+                Err(e) => Some(Err(e.into())),
+            });
+
+        Box::new(entries)
+    }
+}
+
+/// Reads paths separated by `separator`, such as newlines or the NULs of `find -print0`.
+///
+/// Empty entries are skipped, as is the carriage return of a CRLF line ending.
+pub fn read_paths(reader: impl BufRead, separator: u8) -> impl Iterator<Item = Result<PathBuf>> {
+    reader
+        .split(separator)
+        .filter_map(move |entry| match entry {
+            Ok(mut bytes) => {
+                if separator == b'\n' && bytes.last() == Some(&b'\r') {
+                    bytes.pop();
+                }
+                (!bytes.is_empty()).then(|| Ok(path_from_bytes(bytes)))
+            }
+            Err(e) => Some(Err(e.into())),
+        })
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::{ffi::OsString, os::unix::ffi::OsStringExt};
+
+    OsString::from_vec(bytes).into()
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    String::from_utf8_lossy(&bytes).into_owned().into()
+}
+
+fn is_hidden(entry: &DirEntry) -> bool {
+    entry.file_name().as_encoded_bytes().starts_with(b".")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::{read_paths, Walker};
+    use crate::Error;
+
+    fn read(input: &[u8], separator: u8) -> Vec<String> {
+        read_paths(input, separator)
+            .map(|path| path.unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn paths_are_split_on_the_separator() {
+        assert_eq!(read(b"a.jpg\r\nb c.png\n\n", b'\n'), ["a.jpg", "b c.png"]);
+        assert_eq!(read(b"a\nb.jpg\0c.png\0", b'\0'), ["a\nb.jpg", "c.png"]);
+    }
+
+    #[test]
+    fn directories_are_skipped_unless_recursive() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.jpg"), b"").unwrap();
+        let mut walker = Walker {
+            recursive: false,
+            max_depth: None,
+            follow_symlinks: false,
+            include_hidden: false,
+        };
+
+        let paths = || Some(Ok(dir.path().to_owned())).into_iter();
+        let found: Vec<_> = walker.files(paths()).collect();
+        assert!(matches!(&found[..], [Err(Error::SkippedDirectory(path))] if path == dir.path()));
+
+        walker.recursive = true;
+        let found: Vec<_> = walker.files(paths()).map(|found| found.unwrap()).collect();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].relative, Path::new("a.jpg"));
+    }
+}
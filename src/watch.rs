@@ -0,0 +1,129 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+use imgfix::{rename, Result};
+use notify::{
+    event::{AccessKind, AccessMode, ModifyKind},
+    EventKind, RecursiveMode, Watcher,
+};
+
+/// How often to look for files that have settled when no events arrive.
+const TICK: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    pub recursive: bool,
+    pub include_hidden: bool,
+    /// How long a file must go without events or a change in size before it's handled.
+    pub settle: Duration,
+}
+
+/// A file seen changing but not yet handled.
+#[derive(Debug)]
+struct Pending {
+    last_change: Instant,
+    len: Option<u64>,
+}
+
+impl Pending {
+    fn new(path: &Path, now: Instant) -> Self {
+        Pending {
+            last_change: now,
+            len: len(path),
+        }
+    }
+
+    /// Whether the file has gone quiet; a file that's still growing starts the wait over.
+    fn is_settled(&mut self, path: &Path, now: Instant, settle: Duration) -> bool {
+        if now - self.last_change < settle {
+            return false;
+        }
+
+        let len = len(path);
+        if len != self.len {
+            *self = Pending {
+                last_change: now,
+                len,
+            };
+            return false;
+        }
+        true
+    }
+}
+
+/// Calls `handle` on each file created or written under `dir` once it has settled, until the
+/// watch fails or `handle` returns an error.
+///
+/// `handle` returns the paths it wrote, so that its own renames aren't mistaken for new files.
+pub fn watch(
+    dir: &Path,
+    options: Options,
+    mut handle: impl FnMut(PathBuf) -> Result<Vec<PathBuf>>,
+) -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).map_err(io::Error::other)?;
+    let mode = match options.recursive {
+        true => RecursiveMode::Recursive,
+        false => RecursiveMode::NonRecursive,
+    };
+    watcher.watch(dir, mode).map_err(io::Error::other)?;
+
+    let mut pending: HashMap<PathBuf, Pending> = HashMap::new();
+    let mut written = HashSet::new();
+    loop {
+        let now = Instant::now();
+        match rx.recv_timeout(TICK) {
+            Ok(event) => {
+                let event = event.map_err(io::Error::other)?;
+                if is_write(event.kind) {
+                    for path in event.paths {
+                        pending.insert(path.clone(), Pending::new(&path, now));
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+
+        let now = Instant::now();
+        let mut settled: Vec<_> = pending
+            .iter_mut()
+            .filter_map(|(path, file)| file.is_settled(path, now, options.settle).then_some(path))
+            .cloned()
+            .collect();
+        settled.sort();
+
+        for path in settled {
+            pending.remove(&path);
+            if written.remove(&path) || !is_candidate(&path, options.include_hidden) {
+                continue;
+            }
+            written.extend(handle(path)?);
+        }
+    }
+}
+
+/// Whether an event may mean a file has new contents.
+fn is_write(kind: EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Create(_)
+            | EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Name(_) | ModifyKind::Any)
+            | EventKind::Access(AccessKind::Close(AccessMode::Write))
+    )
+}
+
+fn is_candidate(path: &Path, include_hidden: bool) -> bool {
+    let is_file = fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_file());
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    is_file && (include_hidden || !name.starts_with('.')) && !rename::is_temporary(path)
+}
+
+fn len(path: &Path) -> Option<u64> {
+    fs::metadata(path).ok().map(|metadata| metadata.len())
+}